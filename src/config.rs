@@ -2,17 +2,56 @@
 
 pub const USER_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
+/// Boot heap in `.bss.heap`; the heap grows with frames once they can be allocated.
+/// Keep in sync with `kernel_heap_beg` in entry.asm.
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 
+/// Use at most this many page-table levels: 3 for Sv39, 4 for Sv48, 5 for Sv57.
+/// The largest mode the hart supports within this limit is used.
+pub const MAX_PAGING_LEVELS: usize = 5;
+/// Identical and Linear areas use pages up to this level: 1 for 2 MiB, 2 for 1 GiB.
+pub const MAX_HUGE_PAGE_LEVEL: usize = 2;
+
+pub const MAX_HARTS: usize = 8;
+/// S-mode stack of each hart until it switches to a kernel stack.
+/// Keep in sync with `boot_stack` in entry.asm.
+pub const BOOT_STACK_SIZE: usize = 4096 * 16;
+/// M-mode stack of each hart, used by the built-in SBI firmware.
+/// Keep in sync with `firmware_stack` in entry.asm.
+pub const FIRMWARE_STACK_SIZE: usize = 4096 * 4;
+
+pub const CLOCK_FREQ: usize = 10_000_000;
+pub const TICKS_PER_SEC: usize = 100;
+
+/// Lowest kernel virtual address: the upper half of Sv39, which Sv48 and
+/// Sv57 keep at their top. KASLR places the direct map and the kernel image
+/// in here, see mem/kaslr.rs. Keep in sync with linker.ld.
+pub const KERNEL_SPACE_BASE: usize = 0xffff_ffc0_0000_0000;
+/// Physical memory below this is reachable through the direct map.
+pub const DIRECT_MAP_SIZE: usize = 64 << 30;
+
+/// Where `ET_DYN` programs and their interpreters are loaded, out of the way
+/// of `ET_EXEC` files linked low.
+pub const ELF_ET_DYN_BASE: usize = 0x20_0000_0000;
+pub const ELF_INTERP_BASE: usize = 0x30_0000_0000;
+
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
-/// Return (bottom, top) of a kernel stack in kernel space.
+/// Kernel stacks that can exist at once. With the one-page gaps, they stay in
+/// the last GiB of the address space wherever KASLR puts the first.
+/// Keep in sync with trap.S.
+pub const MAX_KERNEL_STACKS: usize = 0x8000;
+/// Stack of each hart for traps taken with a kernel stack overflowing.
+pub const OVERFLOW_STACK_SIZE: usize = 4096 * 4;
+/// Return (bottom, top) of a kernel stack in kernel space, with an unmapped
+/// guard page below.
 pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
-    let top = TRAMPOLINE - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
+    let top = crate::mem::kernel_stack_top() - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
     let bottom = top - KERNEL_STACK_SIZE;
     (bottom, top)
 }
 
-pub const MEMORY_END: usize = 0x0800_0000;
\ No newline at end of file
+/// End of RAM when there is no device tree: QEMU virt with 128 MiB.
+pub const MEMORY_END: usize = 0x8800_0000;
\ No newline at end of file
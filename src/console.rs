@@ -0,0 +1,53 @@
+use core::fmt::{self, Write};
+use crate::drivers::uart::UART;
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        UART.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Stop queueing output, e.g. before panicking: the queue may be locked.
+pub fn set_polled() {
+    UART.set_polled();
+}
+
+pub fn print(args: fmt::Arguments) {
+    Stdout.write_fmt(args).unwrap();
+}
+
+struct PolledStdout;
+
+impl Write for PolledStdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|c| UART.write_polled(c));
+        Ok(())
+    }
+}
+
+/// Print without taking any lock, for code that runs under the console's
+/// own locks, like lockdep.
+#[cfg_attr(not(feature = "lockdep"), allow(unused))]
+pub fn print_polled(args: fmt::Arguments) {
+    PolledStdout.write_fmt(args).unwrap();
+}
+
+#[macro_export]
+macro_rules! print {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!($fmt $(, $($arg)+)?));
+    }
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::console::print(format_args!("\n"));
+    };
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
+    }
+}
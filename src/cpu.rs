@@ -0,0 +1,131 @@
+//! Per-hart state. Every hart keeps a pointer to its own `Cpu` in `tp`,
+//! which nothing else in the kernel uses.
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
+use crate::config::{MAX_HARTS, OVERFLOW_STACK_SIZE};
+use crate::mem::virt_to_phys;
+use crate::platform::PLATFORM;
+use crate::sync::IntrMaskingInfo;
+use crate::{println, sbi, timer};
+
+/// trap.S reaches the first two fields through `tp`, at these offsets.
+#[repr(C)]
+pub struct Cpu {
+    /// Where `__alltraps` keeps t0 and t1 while it picks a stack.
+    trap_scratch: UnsafeCell<[usize; 2]>,
+    /// Top of this hart's stack for traps taken on an overflowing kernel stack.
+    overflow_stack_top: AtomicUsize,
+    pub hartid: usize,
+    intr_masking: UnsafeCell<IntrMaskingInfo>,
+}
+
+pub const TRAP_SCRATCH_OFFSET: usize = core::mem::offset_of!(Cpu, trap_scratch);
+pub const OVERFLOW_STACK_TOP_OFFSET: usize = core::mem::offset_of!(Cpu, overflow_stack_top);
+
+/// A `Cpu` is only ever mutated by its own hart.
+unsafe impl Sync for Cpu {}
+
+impl Cpu {
+    const fn new(hartid: usize) -> Self {
+        Self {
+            trap_scratch: UnsafeCell::new([0; 2]),
+            overflow_stack_top: AtomicUsize::new(0),
+            hartid,
+            intr_masking: UnsafeCell::new(IntrMaskingInfo::new()),
+        }
+    }
+
+    /// Interrupt masking state of this hart; callers must be running on it.
+    #[allow(clippy::mut_from_ref)]
+    pub fn intr_masking(&self) -> &mut IntrMaskingInfo {
+        unsafe { &mut *self.intr_masking.get() }
+    }
+}
+
+static CPUS: [Cpu; MAX_HARTS] = {
+    let mut cpus = [const { Cpu::new(0) }; MAX_HARTS];
+    let mut i = 0;
+    while i < MAX_HARTS {
+        cpus[i] = Cpu::new(i);
+        i += 1;
+    }
+    cpus
+};
+
+#[repr(C, align(16))]
+struct OverflowStack(UnsafeCell<[u8; OVERFLOW_STACK_SIZE]>);
+
+/// Only the hart it belongs to runs on an overflow stack.
+unsafe impl Sync for OverflowStack {}
+
+static OVERFLOW_STACKS: [OverflowStack; MAX_HARTS] =
+    [const { OverflowStack(UnsafeCell::new([0; OVERFLOW_STACK_SIZE])) }; MAX_HARTS];
+
+/// Bit `i` is set once hart `i` has come up in S-mode.
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Point `tp` at this hart's `Cpu` and mark the hart online.
+pub fn init(hartid: usize) {
+    let cpu = &CPUS[hartid];
+    let overflow_stack = OVERFLOW_STACKS[hartid].0.get() as usize;
+    cpu.overflow_stack_top.store(overflow_stack + OVERFLOW_STACK_SIZE, Ordering::Relaxed);
+    unsafe {
+        asm!("mv tp, {}", in(reg) cpu as *const Cpu);
+    }
+    ONLINE_HARTS.fetch_or(1 << hartid, Ordering::AcqRel);
+}
+
+pub fn current() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        asm!("mv {}, tp", out(reg) cpu);
+        &*cpu
+    }
+}
+
+pub fn hartid() -> usize {
+    current().hartid
+}
+
+/// Bitmap of the harts that are up.
+pub fn online_harts() -> usize {
+    ONLINE_HARTS.load(Ordering::Acquire)
+}
+
+pub fn online_hart_ids() -> Vec<usize> {
+    let online = online_harts();
+    (0..MAX_HARTS).filter(|i| online & (1 << i) != 0).collect()
+}
+
+/// Start every other hart listed in the device tree at `secondary_entry`
+/// and wait (up to a second) for them to come online.
+pub fn start_secondaries() {
+    unsafe extern "C" {
+        fn secondary_entry();
+    }
+    let boot_hartid = hartid();
+    let hart_ids = PLATFORM.read().hart_ids.clone();
+    let mut expected = online_harts();
+    for &hartid in hart_ids.iter().filter(|&&id| id != boot_hartid) {
+        if hartid >= MAX_HARTS {
+            println!("[kernel] hart {} is beyond MAX_HARTS, leaving it stopped", hartid);
+            continue;
+        }
+        let ret = sbi::hart_start(hartid, virt_to_phys(secondary_entry as usize), 0);
+        if ret.is_ok() {
+            expected |= 1 << hartid;
+        } else {
+            println!("[kernel] failed to start hart {}: error {}", hartid, ret.error);
+        }
+    }
+    let deadline = timer::now() + timer::NSEC_PER_SEC;
+    while online_harts() & expected != expected && timer::now() < deadline {
+        core::hint::spin_loop();
+    }
+    if online_harts() & expected != expected {
+        println!("[kernel] harts {:#x} did not come online", expected & !online_harts());
+    }
+}
@@ -0,0 +1,31 @@
+pub mod plic;
+pub mod uart;
+
+use riscv::register::sie;
+use crate::platform::PLATFORM;
+use uart::UART;
+
+/// Set up the PLIC and take the console UART off polling, once the trap
+/// handler is installed. Without a PLIC or a UART interrupt it stays polled.
+pub fn init() {
+    let platform = PLATFORM.read();
+    let Some(plic) = platform.plic else {
+        return;
+    };
+    plic::init(plic.base, platform.plic_ndev, &platform.plic_contexts);
+    let uart_irq = platform.uart.and_then(|uart| uart.irq);
+    drop(platform);
+    init_hart();
+    if let Some(irq) = uart_irq {
+        plic::register_irq(irq, || UART.handle_irq());
+        UART.enable_interrupts();
+    }
+}
+
+/// Let the calling hart take device interrupts.
+pub fn init_hart() {
+    plic::init_hart();
+    unsafe {
+        sie::set_sext();
+    }
+}
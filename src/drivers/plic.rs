@@ -0,0 +1,167 @@
+//! Platform-level interrupt controller.
+//!
+//! Every hart that calls `init_hart` takes external interrupts through its
+//! own S-mode context. Registered sources are enabled on all of them, and
+//! whichever hart claims an interrupt first runs the handler.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::config::MAX_HARTS;
+use crate::mem::phys_to_virt;
+use crate::{cpu, println};
+use crate::sync::SpinLockIrq;
+
+/// Sources are numbered 1..1024; 0 means "no interrupt".
+pub const MAX_IRQS: usize = 1024;
+
+const PRIORITY: usize = 0x0;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+/// Priority given to registered sources; anything above the threshold of 0
+/// is delivered.
+const DEFAULT_PRIORITY: u32 = 1;
+
+pub type IrqHandler = fn();
+
+struct Plic {
+    /// physical, accessed through the direct map
+    base: usize,
+    /// Number of sources, `riscv,ndev` in the device tree
+    ndev: usize,
+    /// S-mode context of each hart
+    contexts: [Option<usize>; MAX_HARTS],
+    handlers: [Option<IrqHandler>; MAX_IRQS],
+}
+
+static PLIC: SpinLockIrq<Plic> = SpinLockIrq::new(Plic {
+    base: 0,
+    ndev: 0,
+    contexts: [None; MAX_HARTS],
+    handlers: [None; MAX_IRQS],
+})
+.named("PLIC");
+
+/// Bit `i` is set once hart `i` has set up its context.
+static READY_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        phys_to_virt(self.base + offset) as *mut u32
+    }
+
+    fn context(&self, hartid: usize) -> usize {
+        self.contexts[hartid].expect("Hart has no S-mode PLIC context")
+    }
+
+    fn set_priority(&self, irq: usize, priority: u32) {
+        unsafe { self.reg(PRIORITY + 4 * irq).write_volatile(priority) }
+    }
+
+    fn set_enable(&self, hartid: usize, irq: usize, enable: bool) {
+        let reg = self.reg(ENABLE + ENABLE_STRIDE * self.context(hartid) + 4 * (irq / 32));
+        unsafe {
+            let bits = reg.read_volatile();
+            let bit = 1 << (irq % 32);
+            reg.write_volatile(if enable { bits | bit } else { bits & !bit });
+        }
+    }
+
+    fn set_threshold(&self, hartid: usize, threshold: u32) {
+        let context = self.context(hartid);
+        unsafe { self.reg(CONTEXT + CONTEXT_STRIDE * context + THRESHOLD).write_volatile(threshold) }
+    }
+
+    fn claim(&self, hartid: usize) -> usize {
+        let context = self.context(hartid);
+        unsafe { self.reg(CONTEXT + CONTEXT_STRIDE * context + CLAIM).read_volatile() as usize }
+    }
+
+    fn complete(&self, hartid: usize, irq: usize) {
+        let context = self.context(hartid);
+        unsafe { self.reg(CONTEXT + CONTEXT_STRIDE * context + CLAIM).write_volatile(irq as u32) }
+    }
+
+    fn ready_harts(&self) -> impl Iterator<Item = usize> {
+        let ready = READY_HARTS.load(Ordering::Acquire);
+        (0..MAX_HARTS).filter(move |hartid| ready & (1 << hartid) != 0)
+    }
+}
+
+/// Set up the PLIC at `base` with `ndev` sources; `contexts` lists the
+/// S-mode context of each hart as (hartid, context).
+pub fn init(base: usize, ndev: usize, contexts: &[(usize, usize)]) {
+    let mut plic = PLIC.lock();
+    plic.base = base;
+    plic.ndev = ndev.min(MAX_IRQS - 1);
+    for &(hartid, context) in contexts.iter().filter(|(hartid, _)| *hartid < MAX_HARTS) {
+        plic.contexts[hartid] = Some(context);
+    }
+    for irq in 1..=plic.ndev {
+        plic.set_priority(irq, 0);
+    }
+}
+
+/// Start taking external interrupts on the calling hart: enable every
+/// registered source in its context and open the threshold.
+pub fn init_hart() {
+    let hartid = cpu::hartid();
+    let plic = PLIC.lock();
+    if plic.base == 0 || plic.contexts[hartid].is_none() {
+        return;
+    }
+    for irq in 1..=plic.ndev {
+        plic.set_enable(hartid, irq, plic.handlers[irq].is_some());
+    }
+    plic.set_threshold(hartid, 0);
+    READY_HARTS.fetch_or(1 << hartid, Ordering::AcqRel);
+}
+
+/// Run `handler` in interrupt context whenever source `irq` fires.
+pub fn register_irq(irq: usize, handler: IrqHandler) {
+    let mut plic = PLIC.lock();
+    assert!(irq > 0 && irq <= plic.ndev, "IRQ {} is not a PLIC source", irq);
+    assert!(plic.handlers[irq].is_none(), "IRQ {} is already registered", irq);
+    plic.handlers[irq] = Some(handler);
+    plic.set_priority(irq, DEFAULT_PRIORITY);
+    for hartid in plic.ready_harts() {
+        plic.set_enable(hartid, irq, true);
+    }
+}
+
+#[allow(unused)]
+pub fn unregister_irq(irq: usize) {
+    let mut plic = PLIC.lock();
+    assert!(irq > 0 && irq <= plic.ndev, "IRQ {} is not a PLIC source", irq);
+    for hartid in plic.ready_harts() {
+        plic.set_enable(hartid, irq, false);
+    }
+    plic.set_priority(irq, 0);
+    plic.handlers[irq] = None;
+}
+
+/// Called by the trap handler on a supervisor external interrupt.
+pub fn handle_interrupt() {
+    let hartid = cpu::hartid();
+    loop {
+        // don't hold the lock across the handler, it may register IRQs
+        let (irq, handler) = {
+            let plic = PLIC.lock();
+            let irq = plic.claim(hartid);
+            (irq, plic.handlers[irq])
+        };
+        if irq == 0 {
+            break;
+        }
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                println!("[kernel] spurious IRQ {} on hart {}", irq, hartid);
+            }
+        }
+        PLIC.lock().complete(hartid, irq);
+    }
+}
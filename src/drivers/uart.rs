@@ -0,0 +1,363 @@
+#![allow(dead_code)]
+#![allow(unused)]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::hint::spin_loop;
+use riscv::register::sstatus;
+use volatile::access::{ReadOnly, ReadWrite};
+use volatile::{VolatileFieldAccess, VolatileRef};
+use crate::mem::phys_to_virt;
+use crate::sync::SpinLockIrq;
+
+// UART base address for QEMU virt, used until the device tree says otherwise
+pub const UART_BASE: usize = 0x10000000;
+
+const BS: u8 = 0x8;  // Backspace
+const DEL: u8 = 0x7F; // Delete
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 4096;
+
+/// Read port when DLAB = 0.
+#[repr(C)]
+#[derive(VolatileFieldAccess, Default)]
+struct ReadPort {
+    /// receive buffer
+    rbr: AtomicU8,
+    /// interrupt enable
+    ier: u8,
+    /// interrupt identification
+    #[access(ReadOnly)]
+    iir: u8,
+    /// line control
+    lcr: u8,
+    /// modem control
+    mcr: u8,
+    /// line status
+    lsr: AtomicU8,
+    /// modem status
+    msr: u8,
+    // scratch
+    scr: u8,
+}
+
+/// Write port when DLAB = 0.
+#[repr(C)]
+#[derive(VolatileFieldAccess, Default)]
+struct WritePort {
+    /// transmitter holding
+    thr: AtomicU8,
+    /// interrupt enable
+    ier: u8,
+    /// FIFO control
+    fcr: u8,
+    /// line control
+    lcr: u8,
+    /// modem control
+    mcr: u8,
+    /// line status
+    lsr: AtomicU8,
+    /// not used
+    #[access(ReadOnly)]
+    _padding: u8,
+    // scratch
+    #[access(ReadOnly)]
+    scr: u8,
+}
+
+// Constants for register bit flags
+mod flags {
+    // Interrupt Enable register flags
+    pub const IER_RX_AVAILABLE: u8 = 1 << 0;
+    pub const IER_TX_EMPTY: u8 = 1 << 1;
+
+    // FIFO Control register flags
+    pub const FCR_ENABLE: u8 = 1 << 0;
+    pub const FCR_CLEAR_RX_FIFO: u8 = 1 << 1;
+    pub const FCR_CLEAR_TX_FIFO: u8 = 1 << 2;
+    pub const FCR_TRIGGER_14: u8 = 0b11 << 6;
+
+    // Line Control register flags
+    pub const LCR_DATA_8: u8 = 0b11;
+    pub const LCR_DLAB_ENABLE: u8 = 1 << 7;
+
+    // Modem Control register flags
+    pub const MCR_DATA_TERMINAL_READY: u8 = 1 << 0;
+    pub const MCR_AUXILIARY_OUTPUT_2: u8 = 1 << 3;
+
+    // Line Status register flags
+    pub const LSR_INPUT_AVAILABLE: u8 = 1 << 0;
+    pub const LSR_OUTPUT_EMPTY: u8 = 1 << 5;
+}
+
+/// Fixed-size byte FIFO
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        Self { buf: [0; N], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
+
+/// 16550 UART driver.
+///
+/// Until `enable_interrupts` is called every access polls the line status
+/// register. Afterwards received bytes are buffered by the interrupt
+/// handler, output is queued and drained on THR-empty interrupts, and
+/// readers wait for input with `wfi`. `set_polled` goes back to polling,
+/// which the panic handler does so that it never waits on a lock.
+pub struct Uart {
+    /// physical, accessed through the direct map
+    base: AtomicUsize,
+    irq_driven: AtomicBool,
+    rx: SpinLockIrq<RingBuffer<RX_BUFFER_SIZE>>,
+    tx: SpinLockIrq<RingBuffer<TX_BUFFER_SIZE>>,
+}
+
+impl Uart {
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base: AtomicUsize::new(base),
+            irq_driven: AtomicBool::new(false),
+            rx: SpinLockIrq::new(RingBuffer::new()).named("UART.rx"),
+            tx: SpinLockIrq::new(RingBuffer::new()).named("UART.tx"),
+        }
+    }
+
+    /// Get a reference to the read port
+    fn read_port(&self) -> &'static mut ReadPort {
+        unsafe { &mut *(phys_to_virt(self.base.load(Ordering::Relaxed)) as *mut ReadPort) }
+    }
+
+    /// Get a reference to the write port
+    fn write_port(&self) -> &'static mut WritePort {
+        unsafe { &mut *(phys_to_virt(self.base.load(Ordering::Relaxed)) as *mut WritePort) }
+    }
+
+    /// Initialize the UART at `base` with standard settings, interrupts off
+    pub fn init(&self, base: usize) {
+        self.base.store(base, Ordering::Relaxed);
+        let read_port = self.read_port();
+        let mut read_port = VolatileRef::from_mut_ref(read_port);
+        let read_port = read_port.as_mut_ptr();
+        let write_port = self.write_port();
+        let mut write_port = VolatileRef::from_mut_ref(write_port);
+        let write_port = write_port.as_mut_ptr();
+
+        // disable interrupts
+        read_port.ier().write(0);
+
+        // enable DLAB
+        read_port.lcr().write(flags::LCR_DLAB_ENABLE);
+
+        // set maximum speed of 38.4K for LSB
+        unsafe {
+            (*(read_port.as_raw_ptr().as_ptr())).rbr.store(0x03, Ordering::Release);
+        }
+
+        // set maximum speed of 38.4K for MSB
+        read_port.ier().write(0);
+
+        // disable DLAB and set data word length to 8 bits
+        read_port.lcr().write(flags::LCR_DATA_8);
+
+        // enable FIFO, clear TX/RX queues and set interrupt watermark at 14 bytes
+        // write_port.fcr = flags::FCR_ENABLE | flags::FCR_CLEAR_RX_FIFO |
+        // flags::FCR_CLEAR_TX_FIFO | flags::FCR_TRIGGER_14;
+        write_port.fcr().write(flags::FCR_ENABLE | flags::FCR_CLEAR_RX_FIFO |
+            flags::FCR_CLEAR_TX_FIFO | flags::FCR_TRIGGER_14);
+
+        // mark data terminal ready, signal request to send and enable auxiliary output
+        read_port.mcr().write(flags::MCR_DATA_TERMINAL_READY | flags::MCR_AUXILIARY_OUTPUT_2);
+    }
+
+    /// Switch to interrupt-driven I/O. The caller routes the UART interrupt
+    /// to `handle_irq` first.
+    pub fn enable_interrupts(&self) {
+        self.irq_driven.store(true, Ordering::Release);
+        self.set_ier(flags::IER_RX_AVAILABLE);
+        // pick up whatever arrived while we were polling
+        self.handle_irq();
+    }
+
+    /// Go back to polling, flushing queued output if nobody holds the queue.
+    pub fn set_polled(&self) {
+        if !self.irq_driven.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        self.set_ier(0);
+        if let Some(mut tx) = self.tx.try_lock() {
+            while let Some(byte) = tx.pop() {
+                self.write_polled(byte);
+            }
+        }
+    }
+
+    fn set_ier(&self, ier: u8) {
+        let mut read_port = VolatileRef::from_mut_ref(self.read_port());
+        read_port.as_mut_ptr().ier().write(ier);
+    }
+
+    fn lsr(&self) -> u8 {
+        self.read_port().lsr.load(Ordering::Acquire)
+    }
+
+    fn input_available(&self) -> bool {
+        self.lsr() & flags::LSR_INPUT_AVAILABLE != 0
+    }
+
+    fn output_empty(&self) -> bool {
+        self.lsr() & flags::LSR_OUTPUT_EMPTY != 0
+    }
+
+    /// Called from the external interrupt handler: move received bytes into
+    /// the RX buffer and refill the transmitter from the TX buffer.
+    pub fn handle_irq(&self) {
+        {
+            let mut rx = self.rx.lock();
+            while self.input_available() {
+                let byte = self.read_port().rbr.load(Ordering::Acquire);
+                // drop input nobody reads
+                rx.push(byte);
+            }
+        }
+        let mut tx = self.tx.lock();
+        self.drain(&mut tx);
+    }
+
+    /// Feed THR while it is empty, and only ask for THR-empty interrupts
+    /// while there is output left to send.
+    fn drain(&self, tx: &mut RingBuffer<TX_BUFFER_SIZE>) {
+        while !tx.is_empty() && self.output_empty() {
+            let byte = tx.pop().unwrap();
+            self.write_port().thr.store(byte, Ordering::Release);
+        }
+        if self.irq_driven.load(Ordering::Acquire) {
+            let ier = match tx.is_empty() {
+                true => flags::IER_RX_AVAILABLE,
+                false => flags::IER_RX_AVAILABLE | flags::IER_TX_EMPTY,
+            };
+            self.set_ier(ier);
+        }
+    }
+
+    /// Read a byte from the UART (blocking)
+    pub fn read(&self) -> u8 {
+        loop {
+            if !(self.irq_driven.load(Ordering::Acquire) && sstatus::read().sie()) {
+                if let Some(byte) = self.try_read() {
+                    return byte;
+                }
+                spin_loop();
+                continue;
+            }
+            // Check and sleep with SIE clear, so an RX interrupt in between
+            // stays pending and wakes wfi instead of being handled first.
+            unsafe {
+                sstatus::clear_sie();
+            }
+            let byte = self.try_read();
+            if byte.is_none() {
+                unsafe {
+                    asm!("wfi");
+                }
+            }
+            unsafe {
+                sstatus::set_sie();
+            }
+            if let Some(byte) = byte {
+                return byte;
+            }
+        }
+    }
+
+    /// Read a byte if one is available
+    pub fn try_read(&self) -> Option<u8> {
+        if !self.irq_driven.load(Ordering::Acquire) {
+            return self.input_available().then(|| self.read_port().rbr.load(Ordering::Acquire));
+        }
+        self.rx.lock().pop()
+    }
+
+    /// Write a byte to the UART
+    pub fn write(&self, data: u8) {
+        self.write_bytes(&[data]);
+    }
+
+    /// Write bytes to the UART, queueing them when interrupt-driven
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        if !self.irq_driven.load(Ordering::Acquire) {
+            for &byte in bytes {
+                for &out in Self::expand(&byte) {
+                    self.write_polled(out);
+                }
+            }
+            return;
+        }
+        let mut tx = self.tx.lock();
+        for &byte in bytes {
+            for &out in Self::expand(&byte) {
+                while tx.is_full() {
+                    // interrupts are masked while we hold the lock, so make room ourselves
+                    while !self.output_empty() {
+                        spin_loop();
+                    }
+                    self.drain(&mut tx);
+                }
+                tx.push(out);
+            }
+        }
+        self.drain(&mut tx);
+    }
+
+    fn expand(byte: &u8) -> &[u8] {
+        match *byte {
+            // Send a space to overwrite the previous character, then move the cursor back
+            BS | DEL => &[BS, b' ', BS],
+            _ => core::slice::from_ref(byte),
+        }
+    }
+
+    /// Write a byte, waiting until the transmitter is empty
+    pub fn write_polled(&self, data: u8) {
+        while !self.output_empty() {
+            spin_loop();
+        }
+        self.write_port().thr.store(data, Ordering::Release);
+    }
+}
+
+// Create a global instance
+pub static UART: Uart = Uart::new(UART_BASE);
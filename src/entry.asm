@@ -1,10 +1,79 @@
+    # Everything here runs at the physical address the kernel was loaded
+    # at, so symbols are taken PC-relative with lla.
     .section .text.entry
     .globl _start
 _start:
-    la sp, boot_stack_top
-    # call rust_main
+    # Find out which privilege level we were entered in. Reading mstatus
+    # traps unless we are in M-mode; under OpenSBI the trap lands on stvec,
+    # i.e. the S-mode entry, with a0 = hartid and a1 = dtb intact.
+    lla t0, supervisor_entry
+    csrw stvec, t0
+    csrr t0, mstatus
+
+    # M-mode (-bios none): run the built-in SBI firmware on this hart's
+    # firmware stack, which then enters supervisor_entry in S-mode.
+    csrr a0, mhartid
+    li t1, 8                   # MAX_HARTS
+    bgeu a0, t1, 1f
+    li t1, 4096 * 4            # FIRMWARE_STACK_SIZE
+    mul t0, a0, t1
+    lla sp, firmware_stack_top
+    sub sp, sp, t0
     call setup_machine_mode
-    j .
+    tail firmware_main
+1:
+    # no stack for this hart, keep it out of the way
+    wfi
+    j 1b
+
+    # S-mode entries, with a0 = hartid and paging off. The boot hart comes
+    # in at supervisor_entry, the others at secondary_entry once hart 0
+    # starts them through SBI HSM. Each hart turns on the boot page table
+    # and continues where KASLR put the kernel, on its own boot stack.
+    .align 2
+    .globl supervisor_entry
+supervisor_entry:
+    # Relocate the kernel and fill the boot page table, still at the
+    # physical address and on the physical boot stack.
+    li t1, 8                   # MAX_HARTS
+    bgeu a0, t1, 3f
+    li t1, 4096 * 16           # BOOT_STACK_SIZE
+    mul t2, a0, t1
+    lla sp, boot_stack_top
+    sub sp, sp, t2
+    mv s1, a0
+    mv s2, a1
+    call kaslr_init
+    mv a0, s1
+    mv a1, s2
+    lla t0, rust_main
+    j 2f
+
+    .align 2
+    .globl secondary_entry
+secondary_entry:
+    lla t0, rust_main_secondary
+2:
+    li t1, 8                   # MAX_HARTS
+    bgeu a0, t1, 3f
+    lla t1, boot_page_table
+    srli t1, t1, 12
+    li t2, 8 << 60             # Sv39
+    or t1, t1, t2
+    csrw satp, t1
+    sfence.vma
+    lla t3, KERNEL_OFFSET      # virtual minus physical address of the image
+    ld t3, 0(t3)
+    li t1, 4096 * 16           # BOOT_STACK_SIZE
+    mul t2, a0, t1
+    lla sp, boot_stack_top
+    sub sp, sp, t2
+    add sp, sp, t3
+    add t0, t0, t3
+    jr t0
+3:
+    wfi
+    j 3b
 
     .globl setup_machine_mode
 setup_machine_mode:
@@ -20,12 +89,8 @@ setup_machine_mode:
     li t0, 0x800           # Load value for S-mode (01) in MPP position
     csrs mstatus, t0       # Set MPP bits to S-mode in mstatus
 
-    # 2. Set mepc register to the S-mode entry point
-    #    'mepc' holds the return address for 'mret'.
-    #    We set it to where we want S-mode execution to begin.
-
-    la t0, rust_main           # Load the address of the S-mode entry point into t0
-    csrw mepc, t0              # Write the address to mepc
+    # 2. The S-mode entry point is left to the firmware: firmware_main
+    #    writes mepc and performs the 'mret'.
 
     # 3. Temporarily disable page tables (virtual memory)
     #    Set satp (Supervisor Address Translation and Protection) register to 0.
@@ -38,11 +103,13 @@ setup_machine_mode:
     #    mideleg (Machine Interrupt Delegation) registers to 0xffff.
     #    This delegates exceptions 0-15 and interrupts 0-15 to be handled
     #    directly in S-mode, if possible.
+    #    Exception 9 (ecall from S-mode) is kept in M-mode, since that is
+    #    how S-mode makes SBI calls, so medeleg gets 0xfdff.
     #    Note: For full delegation on RV64, you might use -1 (0xffffffffffffffff).
-    #          Using 0xffff as explicitly requested.
 
+    li t0, 0xfdff
+    csrw medeleg, t0           # Delegate exceptions 0-15 except 9 to S-mode
     li t0, 0xffff
-    csrw medeleg, t0           # Delegate exceptions 0-15 to S-mode
     csrw mideleg, t0           # Delegate interrupts 0-15 to S-mode
 
     # 5. Enable S-mode interrupts in 'sie'
@@ -78,18 +145,50 @@ setup_machine_mode:
     li t0, 0xf                 # Load the configuration value (R=1,W=1,X=1, A=NAPOT)
     csrw pmpcfg0, t0           # Write to pmpcfg0 (configures PMP entry 0)
 
-    # 7. Put hartid in a0
-    csrr a0, mhartid
-
-    # M-mode setup is complete.
-    mret
+    # 7. Install the firmware trap vector and enable the interrupts it
+    #    serves: MSIE for IPIs and HSM wake-ups. MTIE is only set once
+    #    S-mode programs a deadline through the SBI TIME extension.
+    #    'mscratch' keeps the top of this hart's firmware stack while the
+    #    hart runs outside M-mode.
+
+    lla t0, __firmware_trap
+    csrw mtvec, t0             # Direct mode M-mode trap vector
+    csrw mscratch, sp
+    li t0, 1 << 3
+    csrs mie, t0               # Set MSIE; taken in S-mode regardless of mstatus.MIE
+
+    # 8. Let S-mode read the time/cycle/instret counters directly, so
+    #    'rdtime' does not trap into the firmware
+
+    li t0, 0x7
+    csrw mcounteren, t0
+
+    # M-mode setup is complete; firmware_main drops to S-mode.
+    ret
+
+    # Used by every hart until it switches to KERNEL_SPACE, filled by
+    # kaslr_init. Not in .bss, which is cleared while they are in use.
+    .section .data
+    .align 12
+    .globl boot_page_table
+boot_page_table:
+    .space 4096
+    .globl boot_page_table_image
+boot_page_table_image:         # the kernel image in 2 MiB pages
+    .space 4096
 
     .section .bss.stack
     .globl boot_stack_lower_bound
 boot_stack_lower_bound:
-    .space 4096 * 16
+    .space 4096 * 16 * 8       # BOOT_STACK_SIZE * MAX_HARTS
     .globl boot_stack_top
 boot_stack_top:
+    .section .bss.firmware_stack
+    .globl firmware_stack_lower_bound
+firmware_stack_lower_bound:
+    .space 4096 * 4 * 8        # FIRMWARE_STACK_SIZE * MAX_HARTS
+    .globl firmware_stack_top
+firmware_stack_top:
     .section .bss.heap
     .globl kernel_heap_beg
 kernel_heap_beg:
@@ -0,0 +1,298 @@
+//! Allocation-free reader for flattened device tree blobs.
+//!
+//! It borrows the blob and never touches the heap, so the M-mode firmware
+//! can use it before the kernel has initialized anything.
+
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Deepest node nesting we keep `#address-cells`/`#size-cells` for.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    BadVersion,
+    Truncated,
+}
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn be64(data: &[u8], off: usize) -> Option<u64> {
+    Some((be32(data, off)? as u64) << 32 | be32(data, off + 4)? as u64)
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// Read a NUL-terminated string at `off`, returning it and the offset after the NUL.
+fn cstr(data: &[u8], off: usize) -> Option<(&str, usize)> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    let s = str::from_utf8(&rest[..len]).ok()?;
+    Some((s, off + len + 1))
+}
+
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    size: usize,
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// # Safety
+    /// `addr` must point to a readable device tree blob that outlives `'a`.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+        let header = unsafe { core::slice::from_raw_parts(addr as *const u8, 40) };
+        if be32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4).ok_or(FdtError::Truncated)? as usize;
+        Self::from_bytes(unsafe { core::slice::from_raw_parts(addr as *const u8, total_size) })
+    }
+
+    pub fn from_bytes(data: &'a [u8]) -> Result<Self, FdtError> {
+        let field = |i: usize| be32(data, i * 4).ok_or(FdtError::Truncated).map(|v| v as usize);
+        if field(0)? as u32 != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        // the structure block layout used here dates from version 16
+        if field(6)? < 16 {
+            return Err(FdtError::BadVersion);
+        }
+        let (total_size, off_struct, off_strings, off_rsvmap) = (field(1)?, field(2)?, field(3)?, field(4)?);
+        let (size_strings, size_struct) = (field(8)?, field(9)?);
+        let data = data.get(..total_size).ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            size: total_size,
+            structs: data.get(off_struct..off_struct + size_struct).ok_or(FdtError::Truncated)?,
+            strings: data.get(off_strings..off_strings + size_strings).ok_or(FdtError::Truncated)?,
+            mem_rsvmap: data.get(off_rsvmap..).ok_or(FdtError::Truncated)?,
+        })
+    }
+
+    /// Size of the whole blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.size
+    }
+
+    /// Entries of the memory reservation block, as (address, size).
+    pub fn reservations(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        let map = self.mem_rsvmap;
+        (0..)
+            .map(move |i| (be64(map, i * 16), be64(map, i * 16 + 8)))
+            .map_while(|entry| match entry {
+                (Some(addr), Some(size)) if addr != 0 || size != 0 => Some((addr, size)),
+                _ => None,
+            })
+    }
+
+    /// All nodes in depth-first order, the root first.
+    pub fn nodes(&self) -> NodeIter<'a> {
+        NodeIter {
+            fdt: *self,
+            off: 0,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'a>> {
+        self.nodes().find(|node| compatible.iter().any(|c| node.is_compatible(c)))
+    }
+
+    fn string_at(&self, off: usize) -> Option<&'a str> {
+        cstr(self.strings, off).map(|(s, _)| s)
+    }
+}
+
+pub struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+    depth: usize,
+    /// (#address-cells, #size-cells) declared by the open node at each depth
+    cells: [(usize, usize); MAX_DEPTH],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Node<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = be32(structs, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let (name, after_name) = cstr(structs, self.off)?;
+                    self.off = align4(after_name);
+                    let parent_cells = match self.depth {
+                        0 => (2, 1),
+                        d => self.cells[(d - 1).min(MAX_DEPTH - 1)],
+                    };
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        props: self.off,
+                        address_cells: parent_cells.0,
+                        size_cells: parent_cells.1,
+                    };
+                    let own_cells = (
+                        node.property_u32("#address-cells").map_or(2, |v| v as usize),
+                        node.property_u32("#size-cells").map_or(1, |v| v as usize),
+                    );
+                    self.cells[self.depth.min(MAX_DEPTH - 1)] = own_cells;
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = be32(structs, self.off)? as usize;
+                    self.off = align4(self.off + 8 + len);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// Unit name, e.g. `uart@10000000`; empty for the root
+    pub name: &'a str,
+    /// 0 for the root
+    pub depth: usize,
+    /// Offset of the first token after FDT_BEGIN_NODE
+    props: usize,
+    /// Cells per address and size in `reg`, as declared by the parent
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Node<'a> {
+    /// Name without the unit address, e.g. `uart` for `uart@10000000`.
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn properties(&self) -> PropIter<'a> {
+        PropIter { fdt: self.fdt, off: self.props }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|(n, _)| *n == name).map(|(_, value)| value)
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    /// A 32- or 64-bit integer property, e.g. `timebase-frequency`.
+    pub fn property_usize(&self, name: &str) -> Option<usize> {
+        let value = self.property(name)?;
+        match value.len() {
+            8 => be64(value, 0).map(|v| v as usize),
+            _ => be32(value, 0).map(|v| v as usize),
+        }
+    }
+
+    /// String-list property, e.g. `compatible`.
+    pub fn property_strs(&self, name: &str) -> impl Iterator<Item = &'a str> + 'a {
+        self.property(name)
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property_strs("compatible").any(|c| c == compatible)
+    }
+
+    pub fn device_type(&self) -> Option<&'a str> {
+        self.property_strs("device_type").next()
+    }
+
+    /// `status` is absent or "okay"
+    pub fn is_enabled(&self) -> bool {
+        self.property_strs("status").next().is_none_or(|s| s == "okay" || s == "ok")
+    }
+
+    /// Entries of `reg`, as (address, size).
+    pub fn reg(&self) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let value = self.property("reg").unwrap_or(&[]);
+        let (address_cells, size_cells) = (self.address_cells, self.size_cells);
+        let entry_len = (address_cells + size_cells) * 4;
+        (0..value.len().checked_div(entry_len).unwrap_or(0)).map(move |i| {
+            let off = i * entry_len;
+            (
+                read_cells(value, off, address_cells),
+                read_cells(value, off + address_cells * 4, size_cells),
+            )
+        })
+    }
+
+    /// Whether a RISC-V cpu node lists the multi-letter ISA extension `ext`
+    /// (lowercase, e.g. "sstc"), in `riscv,isa-extensions` or in `riscv,isa`.
+    pub fn has_isa_extension(&self, ext: &str) -> bool {
+        if self.property("riscv,isa-extensions").is_some() {
+            return self.property_strs("riscv,isa-extensions").any(|e| e == ext);
+        }
+        // e.g. "rv64imafdch_zicsr_zifencei_sstc"; the first part is single letters
+        self.property_strs("riscv,isa")
+            .next()
+            .is_some_and(|isa| isa.split('_').skip(1).any(|e| e == ext))
+    }
+
+    /// First interrupt specifier cell, which is the IRQ number for the PLIC.
+    pub fn interrupt(&self) -> Option<usize> {
+        self.property_u32("interrupts").map(|v| v as usize)
+    }
+}
+
+fn read_cells(data: &[u8], off: usize, cells: usize) -> usize {
+    (0..cells).fold(0usize, |acc, i| {
+        (acc << 32) | be32(data, off + i * 4).unwrap_or(0) as usize
+    })
+}
+
+pub struct PropIter<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+}
+
+impl<'a> Iterator for PropIter<'a> {
+    type Item = (&'a str, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            match be32(structs, self.off)? {
+                FDT_PROP => {
+                    let len = be32(structs, self.off + 4)? as usize;
+                    let name_off = be32(structs, self.off + 8)? as usize;
+                    let value = structs.get(self.off + 12..self.off + 12 + len)?;
+                    self.off = align4(self.off + 12 + len);
+                    return Some((self.fdt.string_at(name_off)?, value));
+                }
+                FDT_NOP => self.off += 4,
+                // properties always precede child nodes
+                _ => return None,
+            }
+        }
+    }
+}
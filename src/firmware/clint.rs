@@ -0,0 +1,49 @@
+//! Core-local interruptor: machine software and timer interrupts.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::{mie, mip};
+
+const MSIP_OFFSET: usize = 0x0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+
+/// QEMU virt default, replaced by the device tree on the boot hart
+#[unsafe(link_section = ".data.firmware")]
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(0x200_0000);
+
+pub fn set_base(base: usize) {
+    CLINT_BASE.store(base, Ordering::Release);
+}
+
+fn msip(hartid: usize) -> *mut u32 {
+    (CLINT_BASE.load(Ordering::Acquire) + MSIP_OFFSET + 4 * hartid) as *mut u32
+}
+
+fn mtimecmp(hartid: usize) -> *mut u64 {
+    (CLINT_BASE.load(Ordering::Acquire) + MTIMECMP_OFFSET + 8 * hartid) as *mut u64
+}
+
+pub fn set_msip(hartid: usize) {
+    unsafe { msip(hartid).write_volatile(1) }
+}
+
+pub fn clear_msip(hartid: usize) {
+    unsafe { msip(hartid).write_volatile(0) }
+}
+
+/// SBI `set_timer`: arm the machine timer and retract any STIP already injected.
+pub fn set_timer(hartid: usize, stime_value: u64) {
+    unsafe {
+        mtimecmp(hartid).write_volatile(stime_value);
+        mip::clear_stimer();
+        mie::set_mtimer();
+    }
+}
+
+/// Forward the machine timer interrupt to S-mode. MTIE stays off until
+/// S-mode programs the next deadline, since MTIP is level-triggered.
+pub fn handle_timer() {
+    unsafe {
+        mie::clear_mtimer();
+        mip::set_stimer();
+    }
+}
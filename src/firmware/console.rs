@@ -0,0 +1,51 @@
+//! Polled 16550 UART used by the DBCN extension and for firmware messages.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const RBR: usize = 0;
+const THR: usize = 0;
+const LSR: usize = 5;
+const LSR_INPUT_AVAILABLE: u8 = 1 << 0;
+const LSR_OUTPUT_EMPTY: u8 = 1 << 5;
+
+/// QEMU virt default, replaced by the device tree on the boot hart
+#[unsafe(link_section = ".data.firmware")]
+static UART_BASE: AtomicUsize = AtomicUsize::new(0x1000_0000);
+
+pub fn set_base(base: usize) {
+    UART_BASE.store(base, Ordering::Release);
+}
+
+fn reg(offset: usize) -> *mut u8 {
+    (UART_BASE.load(Ordering::Acquire) + offset) as *mut u8
+}
+
+pub fn write_byte(byte: u8) {
+    unsafe {
+        while reg(LSR).read_volatile() & LSR_OUTPUT_EMPTY == 0 {}
+        reg(THR).write_volatile(byte);
+    }
+}
+
+pub fn read_byte() -> Option<u8> {
+    unsafe {
+        if reg(LSR).read_volatile() & LSR_INPUT_AVAILABLE == 0 {
+            None
+        } else {
+            Some(reg(RBR).read_volatile())
+        }
+    }
+}
+
+// No `core::fmt` for firmware messages: it calls through vtables, which
+// hold the kernel's virtual addresses.
+pub fn print_str(s: &str) {
+    s.bytes().for_each(write_byte);
+}
+
+pub fn print_hex(value: usize) {
+    print_str("0x");
+    for shift in (0..usize::BITS).step_by(4).rev() {
+        write_byte(b"0123456789abcdef"[(value >> shift) & 0xf]);
+    }
+}
@@ -0,0 +1,94 @@
+//! SBI call dispatch by extension and function ID.
+
+use crate::sbi::*;
+use super::{clint, console, hsm, ipi, read_csr, system_reset};
+
+/// SBI specification v2.0
+const SPEC_VERSION: usize = 2 << 24;
+/// Not a registered SBI implementation ID.
+const IMPL_ID: usize = 0xac05;
+const IMPL_VERSION: usize = 1;
+
+const EXTENSIONS: [usize; 7] = [EID_BASE, EID_TIME, EID_IPI, EID_RFENCE, EID_HSM, EID_SRST, EID_DBCN];
+
+pub fn handle(hartid: usize, eid: usize, fid: usize, args: [usize; 6]) -> SbiRet {
+    match eid {
+        EID_BASE => base(fid, args[0]),
+        EID_TIME if fid == TIME_SET_TIMER => {
+            clint::set_timer(hartid, args[0] as u64);
+            SbiRet::success(0)
+        }
+        EID_IPI if fid == IPI_SEND_IPI => ipi::send(hartid, args[0], args[1], ipi::IPI_SSOFT),
+        EID_RFENCE => match fid {
+            RFENCE_REMOTE_FENCE_I => ipi::send(hartid, args[0], args[1], ipi::IPI_FENCE_I),
+            // flushing everything is a valid implementation of a ranged fence
+            RFENCE_REMOTE_SFENCE_VMA | RFENCE_REMOTE_SFENCE_VMA_ASID => {
+                ipi::send(hartid, args[0], args[1], ipi::IPI_SFENCE_VMA)
+            }
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        },
+        EID_HSM => match fid {
+            HSM_HART_START => hsm::hart_start(args[0], args[1], args[2]),
+            HSM_HART_STOP => hsm::hart_stop(hartid),
+            HSM_HART_GET_STATUS => hsm::hart_get_status(args[0]),
+            HSM_HART_SUSPEND => hsm::hart_suspend(args[0]),
+            _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+        },
+        EID_SRST if fid == SRST_SYSTEM_RESET => srst(args[0], args[1]),
+        EID_DBCN => dbcn(fid, args[0], args[1], args[2]),
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn base(fid: usize, arg0: usize) -> SbiRet {
+    let value = match fid {
+        BASE_GET_SPEC_VERSION => SPEC_VERSION,
+        BASE_GET_IMPL_ID => IMPL_ID,
+        BASE_GET_IMPL_VERSION => IMPL_VERSION,
+        BASE_PROBE_EXTENSION => EXTENSIONS.contains(&arg0) as usize,
+        BASE_GET_MVENDORID => read_csr!("mvendorid"),
+        BASE_GET_MARCHID => read_csr!("marchid"),
+        BASE_GET_MIMPID => read_csr!("mimpid"),
+        _ => return SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    };
+    SbiRet::success(value)
+}
+
+fn srst(reset_type: usize, reason: usize) -> SbiRet {
+    match reset_type {
+        RESET_TYPE_SHUTDOWN => system_reset(false, reason == RESET_REASON_NO_REASON),
+        RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => system_reset(true, true),
+        _ => SbiRet::error(SBI_ERR_INVALID_PARAM),
+    }
+}
+
+fn dbcn(fid: usize, num_bytes: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    // paging is off in M-mode, so the physical address is usable as is
+    if base_hi != 0 && fid != DBCN_CONSOLE_WRITE_BYTE {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    }
+    match fid {
+        DBCN_CONSOLE_WRITE => {
+            let bytes = unsafe { core::slice::from_raw_parts(base_lo as *const u8, num_bytes) };
+            bytes.iter().for_each(|&b| console::write_byte(b));
+            SbiRet::success(num_bytes)
+        }
+        DBCN_CONSOLE_READ => {
+            let buf = unsafe { core::slice::from_raw_parts_mut(base_lo as *mut u8, num_bytes) };
+            let mut count = 0;
+            while count < buf.len() {
+                match console::read_byte() {
+                    Some(b) => buf[count] = b,
+                    None => break,
+                }
+                count += 1;
+            }
+            SbiRet::success(count)
+        }
+        DBCN_CONSOLE_WRITE_BYTE => {
+            console::write_byte(num_bytes as u8);
+            SbiRet::success(0)
+        }
+        _ => SbiRet::error(SBI_ERR_NOT_SUPPORTED),
+    }
+}
@@ -0,0 +1,102 @@
+//! Hart state management. Harts other than the boot hart start out stopped,
+//! waiting in `park` for S-mode to `hart_start` them.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::mie;
+use crate::config::MAX_HARTS;
+use crate::sbi::*;
+use super::{clint, enter_supervisor};
+
+/// The hart never checked in, e.g. QEMU was started with fewer harts.
+const HART_STATE_ABSENT: usize = usize::MAX;
+
+#[unsafe(link_section = ".data.firmware")]
+static HART_STATE: [AtomicUsize; MAX_HARTS] =
+    [const { AtomicUsize::new(HART_STATE_ABSENT) }; MAX_HARTS];
+#[unsafe(link_section = ".data.firmware")]
+static START_ADDR: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+#[unsafe(link_section = ".data.firmware")]
+static START_OPAQUE: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+pub fn boot(hartid: usize) {
+    HART_STATE[hartid].store(HART_STATE_STARTED, Ordering::Release);
+}
+
+pub fn is_started(hartid: usize) -> bool {
+    hartid < MAX_HARTS && HART_STATE[hartid].load(Ordering::Acquire) == HART_STATE_STARTED
+}
+
+/// Wait in M-mode until another hart starts this one.
+pub fn park(hartid: usize) -> ! {
+    unsafe {
+        mie::clear_mtimer();
+        // wake up from `wfi` on IPIs; mstatus.MIE is clear, so no trap is taken
+        mie::set_msoft();
+    }
+    HART_STATE[hartid].store(HART_STATE_STOPPED, Ordering::Release);
+    loop {
+        clint::clear_msip(hartid);
+        if HART_STATE[hartid].load(Ordering::Acquire) == HART_STATE_START_PENDING {
+            let entry = START_ADDR[hartid].load(Ordering::Relaxed);
+            let opaque = START_OPAQUE[hartid].load(Ordering::Relaxed);
+            HART_STATE[hartid].store(HART_STATE_STARTED, Ordering::Release);
+            enter_supervisor(hartid, entry, opaque);
+        }
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    if hartid >= MAX_HARTS {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    }
+    START_ADDR[hartid].store(start_addr, Ordering::Relaxed);
+    START_OPAQUE[hartid].store(opaque, Ordering::Relaxed);
+    match HART_STATE[hartid].compare_exchange(
+        HART_STATE_STOPPED,
+        HART_STATE_START_PENDING,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => {
+            clint::set_msip(hartid);
+            SbiRet::success(0)
+        }
+        Err(HART_STATE_ABSENT) => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        Err(_) => SbiRet::error(SBI_ERR_ALREADY_AVAILABLE),
+    }
+}
+
+/// Only returns on failure; on success the hart parks until restarted.
+pub fn hart_stop(hartid: usize) -> SbiRet {
+    if !is_started(hartid) {
+        return SbiRet::error(SBI_ERR_FAILED);
+    }
+    HART_STATE[hartid].store(HART_STATE_STOP_PENDING, Ordering::Release);
+    park(hartid)
+}
+
+pub fn hart_get_status(hartid: usize) -> SbiRet {
+    if hartid >= MAX_HARTS {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    }
+    match HART_STATE[hartid].load(Ordering::Acquire) {
+        HART_STATE_ABSENT => SbiRet::error(SBI_ERR_INVALID_PARAM),
+        state => SbiRet::success(state),
+    }
+}
+
+/// Only the default retentive suspend is supported: wait for an interrupt
+/// and resume right after the `ecall`.
+pub fn hart_suspend(suspend_type: usize) -> SbiRet {
+    if suspend_type != 0 {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    unsafe {
+        asm!("wfi");
+    }
+    SbiRet::success(0)
+}
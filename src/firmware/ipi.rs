@@ -0,0 +1,81 @@
+//! Inter-processor requests (IPI and RFENCE), delivered through CLINT msip.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::mip;
+use crate::config::MAX_HARTS;
+use crate::sbi::*;
+use super::{clint, hsm};
+
+pub const IPI_SSOFT: usize = 1 << 0;
+pub const IPI_FENCE_I: usize = 1 << 1;
+pub const IPI_SFENCE_VMA: usize = 1 << 2;
+
+/// Requests each hart has yet to process.
+#[unsafe(link_section = ".data.firmware")]
+static PENDING: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+/// Bitmap of the started harts selected by an SBI hart mask, or `None` if
+/// it names any hart that is not available.
+fn target_harts(hart_mask: usize, hart_mask_base: usize) -> Option<usize> {
+    if hart_mask_base == usize::MAX {
+        return Some((0..MAX_HARTS).filter(|&h| hsm::is_started(h)).fold(0, |m, h| m | 1 << h));
+    }
+    let mut targets = 0;
+    for bit in 0..usize::BITS as usize {
+        if hart_mask & (1 << bit) == 0 {
+            continue;
+        }
+        let hartid = hart_mask_base.checked_add(bit)?;
+        if !hsm::is_started(hartid) {
+            return None;
+        }
+        targets |= 1 << hartid;
+    }
+    Some(targets)
+}
+
+/// Post `request` to the selected harts. Fences are synchronous: wait until
+/// every target has carried them out.
+pub fn send(hartid: usize, hart_mask: usize, hart_mask_base: usize, request: usize) -> SbiRet {
+    let Some(targets) = target_harts(hart_mask, hart_mask_base) else {
+        return SbiRet::error(SBI_ERR_INVALID_PARAM);
+    };
+    for target in (0..MAX_HARTS).filter(|t| targets & (1 << t) != 0) {
+        PENDING[target].fetch_or(request, Ordering::Release);
+        clint::set_msip(target);
+    }
+    if request != IPI_SSOFT {
+        for target in (0..MAX_HARTS).filter(|t| targets & (1 << t) != 0) {
+            while PENDING[target].load(Ordering::Acquire) & request != 0 {
+                // serve requests aimed at us, or two harts fencing each other deadlock
+                handle_ipi(hartid);
+            }
+        }
+    }
+    SbiRet::success(0)
+}
+
+pub fn handle_ipi(hartid: usize) {
+    clint::clear_msip(hartid);
+    let pending = PENDING[hartid].load(Ordering::Acquire);
+    if pending == 0 {
+        return;
+    }
+    if pending & IPI_SSOFT != 0 {
+        unsafe {
+            mip::set_ssoft();
+        }
+    }
+    if pending & IPI_FENCE_I != 0 {
+        unsafe {
+            asm!("fence.i");
+        }
+    }
+    if pending & IPI_SFENCE_VMA != 0 {
+        unsafe {
+            asm!("sfence.vma");
+        }
+    }
+    PENDING[hartid].fetch_and(!pending, Ordering::Release);
+}
@@ -0,0 +1,148 @@
+//! Minimal M-mode SBI implementation, used when QEMU boots us with `-bios none`.
+//!
+//! Everything here runs in M-mode with paging off. Mutable state lives in
+//! `.data.firmware`, since the kernel clears `.bss` while other harts may
+//! already be waiting in the firmware.
+//!
+//! The image is linked at the kernel's virtual address but the firmware runs
+//! at the physical one, where only PC-relative addressing works. Addresses
+//! stored in the image are virtual, so the firmware must not use `core::fmt`,
+//! trait objects or constants holding pointers, like a `&[&str]` literal or
+//! the `&"..."` that comparing a `&str` with a literal takes.
+
+mod clint;
+mod console;
+mod ecall;
+mod hsm;
+mod ipi;
+mod trap;
+
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{mepc, mscratch, mstatus};
+use crate::config::FIRMWARE_STACK_SIZE;
+use crate::fdt::Fdt;
+
+global_asm!(include_str!("trap.S"));
+
+/// Hart that boots the kernel; the others wait in `hsm::park` until started.
+const BOOT_HART_ID: usize = 0;
+
+/// SiFive test device; QEMU virt default, replaced by the device tree
+#[unsafe(link_section = ".data.firmware")]
+static TEST_BASE: AtomicUsize = AtomicUsize::new(0x10_0000);
+const TEST_PASS: u32 = 0x5555;
+const TEST_FAIL: u32 = 0x3333;
+const TEST_RESET: u32 = 0x7777;
+
+/// The harts implement Sstc, so S-mode may use `stimecmp` directly
+#[unsafe(link_section = ".data.firmware")]
+static SSTC: AtomicBool = AtomicBool::new(false);
+/// menvcfg.STCE
+const MENVCFG_STCE: usize = 1 << 63;
+/// The harts implement Svpbmt, so S-mode may pick memory types in PTEs
+#[unsafe(link_section = ".data.firmware")]
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+/// menvcfg.PBMTE
+const MENVCFG_PBMTE: usize = 1 << 62;
+
+macro_rules! read_csr {
+    ($csr: literal) => {{
+        let value: usize;
+        unsafe { core::arch::asm!(concat!("csrr {}, ", $csr), out(reg) value) };
+        value
+    }};
+}
+use read_csr;
+
+unsafe extern "C" {
+    fn firmware_stack_top();
+    fn supervisor_entry();
+}
+
+/// Top of the firmware stack of `hartid`, kept in `mscratch` outside M-mode.
+fn stack_top(hartid: usize) -> usize {
+    firmware_stack_top as usize - hartid * FIRMWARE_STACK_SIZE
+}
+
+/// Entered from `_start` in M-mode, on this hart's firmware stack.
+/// `_start` keeps harts beyond `MAX_HARTS` from getting here.
+#[unsafe(no_mangle)]
+pub extern "C" fn firmware_main(hartid: usize, dtb_pa: usize) -> ! {
+    if hartid == BOOT_HART_ID {
+        probe_devices(dtb_pa);
+        hsm::boot(hartid);
+        enter_supervisor(hartid, supervisor_entry as usize, dtb_pa)
+    } else {
+        hsm::park(hartid)
+    }
+}
+
+/// Locate the devices the firmware drives. Runs before the other harts are
+/// started, which until then only touch their own CLINT msip.
+fn probe_devices(dtb_pa: usize) {
+    if dtb_pa == 0 {
+        return;
+    }
+    let Ok(fdt) = (unsafe { Fdt::from_addr(dtb_pa) }) else {
+        return;
+    };
+    // one name at a time: the array passed on is built on the stack
+    let base = |compatible: &str| Some(fdt.find_compatible(&[compatible])?.reg().next()?.0);
+    if let Some(clint) = base("riscv,clint0").or_else(|| base("sifive,clint0")) {
+        clint::set_base(clint);
+    }
+    if let Some(uart) = base("ns16550a").or_else(|| base("ns16550")) {
+        console::set_base(uart);
+    }
+    if let Some(test) = base("sifive,test0").or_else(|| base("sifive,test1")) {
+        TEST_BASE.store(test, Ordering::Release);
+    }
+    let every_cpu_has = |ext| {
+        // not `== Some("cpu")`, which reads the literal from a constant
+        let mut cpus = fdt.nodes().filter(|node| node.device_type().is_some_and(|t| *t == *"cpu")).peekable();
+        cpus.peek().is_some() && cpus.all(|cpu| cpu.has_isa_extension(ext))
+    };
+    SSTC.store(every_cpu_has("sstc"), Ordering::Release);
+    SVPBMT.store(every_cpu_has("svpbmt"), Ordering::Release);
+}
+
+/// Switch to S-mode at `entry` with `a0 = hartid` and `a1 = opaque`, paging off.
+fn enter_supervisor(hartid: usize, entry: usize, opaque: usize) -> ! {
+    if SSTC.load(Ordering::Acquire) {
+        // menvcfg only exists from privileged spec 1.12, as does Sstc
+        unsafe { asm!("csrs 0x30a, {}", in(reg) MENVCFG_STCE) };
+    }
+    if SVPBMT.load(Ordering::Acquire) {
+        unsafe { asm!("csrs 0x30a, {}", in(reg) MENVCFG_PBMTE) };
+    }
+    unsafe {
+        mstatus::set_mpp(mstatus::MPP::Supervisor);
+        mepc::write(entry);
+        mscratch::write(stack_top(hartid));
+        asm!(
+            "csrw satp, zero",
+            "mret",
+            in("a0") hartid,
+            in("a1") opaque,
+            options(noreturn)
+        );
+    }
+}
+
+/// Power off (or reboot) through the SiFive test device of QEMU virt.
+fn system_reset(reboot: bool, success: bool) -> ! {
+    let code = match (reboot, success) {
+        (true, _) => TEST_RESET,
+        (false, true) => TEST_PASS,
+        (false, false) => TEST_FAIL,
+    };
+    unsafe {
+        (TEST_BASE.load(Ordering::Acquire) as *mut u32).write_volatile(code);
+    }
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
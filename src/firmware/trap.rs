@@ -0,0 +1,42 @@
+use riscv::interrupt::machine::{Exception, Interrupt};
+use riscv::interrupt::Trap;
+use riscv::register::{mcause, mhartid, mtval};
+use super::{clint, console, ecall, ipi, system_reset};
+
+/// Registers saved by `__firmware_trap` on the firmware stack.
+#[repr(C)]
+pub struct FirmwareContext {
+    /// General-purpose registers x0~x31
+    pub x: [usize; 32],
+    pub mepc: usize,
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn firmware_trap_handler(cx: &mut FirmwareContext) {
+    let hartid = mhartid::read();
+    let mcause = mcause::read();
+    match mcause.cause().try_into::<Interrupt, Exception>() {
+        Ok(Trap::Interrupt(Interrupt::MachineTimer)) => clint::handle_timer(),
+        Ok(Trap::Interrupt(Interrupt::MachineSoft)) => ipi::handle_ipi(hartid),
+        Ok(Trap::Exception(Exception::SupervisorEnvCall)) => {
+            // a7 = EID, a6 = FID, a0~a5 = arguments
+            let args = [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]];
+            let ret = ecall::handle(hartid, cx.x[17], cx.x[16], args);
+            cx.x[10] = ret.error as usize;
+            cx.x[11] = ret.value;
+            cx.mepc += 4;
+        }
+        _ => {
+            console::print_str("[firmware] hart ");
+            console::print_hex(hartid);
+            console::print_str(": unexpected trap, mcause = ");
+            console::print_hex(mcause.bits());
+            console::print_str(", mtval = ");
+            console::print_hex(mtval::read());
+            console::print_str(", mepc = ");
+            console::print_hex(cx.mepc);
+            console::print_str("\n");
+            system_reset(false, false);
+        }
+    }
+}
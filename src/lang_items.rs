@@ -1,9 +1,10 @@
 use core::panic::PanicInfo;
-use crate::sbi::UART;
+use crate::sbi::shutdown;
 use crate::println;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    crate::console::set_polled();
     if let Some(location) = info.location() {
         println!(
             "\x1b[1;31mPanicked at {}:{}\n{}\x1b[0m",
@@ -15,5 +16,5 @@ fn panic(info: &PanicInfo) -> ! {
         println!("\x1b[1;31mPanicked:\n{:?}\x1b[0m",
                  info.message());
     }
-    UART.shutdown(false)
+    shutdown(false)
 }
@@ -1,6 +1,8 @@
 #![no_std]
 #![no_main]
 #![feature(alloc_error_handler)]
+// Linker symbols are declared as functions and used by their address
+#![allow(function_casts_as_integer)]
 
 extern crate alloc;
 
@@ -8,27 +10,84 @@ extern crate alloc;
 extern crate bitflags;
 
 mod lang_items;
+mod console;
+mod cpu;
+mod drivers;
+mod firmware;
 mod sbi;
 mod mem;
 mod config;
+mod fdt;
+mod platform;
 mod sync;
+mod trap;
+mod timer;
 
 use core::arch::global_asm;
-use crate::sbi::UART;
+use crate::drivers::uart::{UART, UART_BASE};
 
 global_asm!(include_str!("entry.asm"));
 
-#[allow(unused_variables)]
 #[unsafe(no_mangle)]
 pub extern "C" fn rust_main(hart_id: usize, dtb_pa: usize) -> ! {
     clear_bss();
-    UART.init();
-    assert_eq!(hart_id, 0, "Only hart 0 is supported, but got {}", hart_id);
+    cpu::init(hart_id);
     mem::heap_allocator::init_heap();
+    let dtb = platform::init(dtb_pa);
+    mem::init_frame_allocator();
+    mem::init_paging_mode();
+    mem::KERNEL_SPACE.exclusive_access().activate();
+    UART.init(platform::PLATFORM.read().uart.map_or(UART_BASE, |uart| uart.base));
+    trap::init();
+    timer::init();
+    drivers::init();
+    let (major, minor) = sbi::spec_version();
+    println!("[kernel] SBI v{}.{}, implementation {:#x}", major, minor, sbi::impl_id());
+    if let Err(err) = dtb {
+        println!("[kernel] No usable device tree at {:#x} ({:?}), assuming QEMU virt", dtb_pa, err);
+    }
+    platform::print_info();
+    mem::kaslr::print_info();
+    cpu::start_secondaries();
+    println!("[kernel] boot hart {}, harts online: {:?}", hart_id, cpu::online_hart_ids());
+    timer::enable_timer_interrupt();
+    trap::trap_test();
+    sync::lock_test();
+    sync::lockdep_test();
     test_io();
     // mem::heap_allocator::heap_test();
-    test_mtime();
-    UART.shutdown(true)
+    mem::frame_alloc_contiguous_test();
+    mem::page_table_test();
+    mem::page_table_teardown_test();
+    mem::remap_test();
+    mem::kaslr::kaslr_test();
+    mem::kernel_stack_test();
+    mem::elf_loader_test();
+    mem::heap_allocator::heap_grow_test();
+    #[cfg(feature = "heap-debug")]
+    mem::heap_allocator::heap_debug_test();
+    mem::slab_test();
+    mem::meminfo_test();
+    timer::timer_test();
+    sbi::shutdown(true)
+}
+
+/// Entered by the other harts once the boot hart has started them.
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_main_secondary(hart_id: usize, _opaque: usize) -> ! {
+    cpu::init(hart_id);
+    mem::KERNEL_SPACE.exclusive_access().activate();
+    trap::init();
+    timer::init_hart();
+    drivers::init_hart();
+    unsafe {
+        riscv::register::sstatus::set_sie();
+    }
+    loop {
+        unsafe {
+            core::arch::asm!("wfi");
+        }
+    }
 }
 
 fn clear_bss() {
@@ -51,7 +110,7 @@ fn test_io() {
     println!("Please input a character:");
     let c: u8 = UART.read();
     println!("Read: {}", c as char);
-    #[allow(unreachable_code)]
+    #[allow(unreachable_code, clippy::diverging_sub_expression)]
     if c == 0x61 {
         !panic!("You input '{}' (panic test)", c as char);
         // !panic!();
@@ -66,58 +125,3 @@ fn test_large_output() {
         print!("Hello, world {}!", i);
     }
 }
-
-use core::ptr::{read_volatile, write_volatile};
-use core::arch::asm;
-
-#[allow(unused)]
-fn test_mtime() {
-    let mtime_addr: *const u64 = 0x0200bff8 as *const u64;
-
-    // Get the current hartid (CPU ID)
-    let hartid: usize = 0;
-    // unsafe {
-    //     asm!("csrr {}, mhartid", out(reg) hartid);
-    // }
-
-    println!("Got hartid: {}", hartid);
-
-    let mtimecmp_addr: *mut u64 = (0x02004000 + 8 * hartid) as *mut u64;
-
-    println!("Attempting to access timer registers from S-mode...");
-
-    // Try to read mtime
-    let mtime_value = unsafe {
-        read_volatile(mtime_addr)
-    };
-    println!("Current mtime: {}", mtime_value);
-
-    // Try to read mtimecmp
-    let mtimecmp_value = unsafe {
-        read_volatile(mtimecmp_addr)
-    };
-    println!("Current mtimecmp: {}", mtimecmp_value);
-
-    // Calculate new mtimecmp value
-    let new_mtimecmp = mtime_value + 1000000; // 0.1s interval
-
-    println!("Attempting to set mtimecmp to: {}", new_mtimecmp);
-
-    // Try to write to mtimecmp
-    unsafe {
-        write_volatile(mtimecmp_addr, new_mtimecmp);
-    }
-
-    // Verify the write by reading back
-    let mtimecmp_after = unsafe {
-        read_volatile(mtimecmp_addr)
-    };
-    println!("mtimecmp after write: {}", mtimecmp_after);
-
-    // Check if our write was successful
-    if mtimecmp_after == new_mtimecmp {
-        println!("Successfully modified mtimecmp!");
-    } else {
-        println!("Failed to modify mtimecmp. This is expected if running in S-mode.");
-    }
-}
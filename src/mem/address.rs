@@ -1,12 +1,35 @@
 use core::fmt;
 use core::fmt::{Debug, Formatter};
 use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
-use crate::mem::page_table::PageTableEntry;
+use crate::mem::kaslr::{in_image, kernel_offset, phys_virt_offset};
+use crate::mem::page_table::{paging_mode, PageTableEntry};
 
-const PA_WIDTH_SV39: usize = 56;
-const VA_WIDTH_SV39: usize = 39;
-const PPN_WIDTH_SV39: usize = PA_WIDTH_SV39 - PAGE_SIZE_BITS;
-const VPN_WIDTH_SV39: usize = VA_WIDTH_SV39 - PAGE_SIZE_BITS;
+/// The same for Sv39, Sv48 and Sv57; the virtual widths depend on the paging mode.
+const PA_WIDTH: usize = 56;
+const PPN_WIDTH: usize = PA_WIDTH - PAGE_SIZE_BITS;
+
+fn va_width() -> usize {
+    paging_mode().va_bits()
+}
+
+fn vpn_width() -> usize {
+    va_width() - PAGE_SIZE_BITS
+}
+
+/// Where the physical address `pa` is in the direct map.
+pub fn phys_to_virt(pa: usize) -> usize {
+    pa + phys_virt_offset()
+}
+
+/// Physical address of `va`, which must be in the kernel image or the
+/// direct map.
+pub fn virt_to_phys(va: usize) -> usize {
+    if in_image(va) {
+        va - kernel_offset()
+    } else {
+        va - phys_virt_offset()
+    }
+}
 
 /// Definitions
 #[repr(C)]
@@ -25,7 +48,7 @@ pub struct PhysPageNum(pub usize);
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct VirtPageNum(pub usize);
 
-/// Debugging
+// Debugging
 
 impl Debug for VirtAddr {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
@@ -48,28 +71,28 @@ impl Debug for PhysPageNum {
     }
 }
 
-/// T: {PhysAddr, VirtAddr, PhysPageNum, VirtPageNum}
-/// T -> usize: T.0
-/// usize -> T: usize.into()
+// T: {PhysAddr, VirtAddr, PhysPageNum, VirtPageNum}
+// T -> usize: T.0
+// usize -> T: usize.into()
 
 impl From<usize> for PhysAddr {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << PA_WIDTH_SV39) - 1))
+        Self(v & ((1 << PA_WIDTH) - 1))
     }
 }
 impl From<usize> for PhysPageNum {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << PPN_WIDTH_SV39) - 1))
+        Self(v & ((1 << PPN_WIDTH) - 1))
     }
 }
 impl From<usize> for VirtAddr {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << VA_WIDTH_SV39) - 1))
+        Self(v & ((1 << va_width()) - 1))
     }
 }
 impl From<usize> for VirtPageNum {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << VPN_WIDTH_SV39) - 1))
+        Self(v & ((1 << vpn_width()) - 1))
     }
 }
 impl From<PhysAddr> for usize {
@@ -84,8 +107,8 @@ impl From<PhysPageNum> for usize {
 }
 impl From<VirtAddr> for usize {
     fn from(v: VirtAddr) -> Self {
-        if v.0 >= (1 << (VA_WIDTH_SV39 - 1)) {
-            v.0 | (!((1 << VA_WIDTH_SV39) - 1))
+        if v.0 >= (1 << (va_width() - 1)) {
+            v.0 | (!((1 << va_width()) - 1))
         } else {
             v.0
         }
@@ -157,33 +180,29 @@ impl From<PhysPageNum> for PhysAddr {
 }
 
 impl VirtPageNum {
-    pub fn indexes(&self) -> [usize; 3] {
-        let mut vpn = self.0;
-        let mut idx = [0usize; 3];
-        for i in (0..3).rev() {
-            idx[i] = vpn & 511;
-            vpn >>= 9;
-        }
-        idx
+    /// Index into the page table at `level`, 0 being the leaf level.
+    pub fn index(&self, level: usize) -> usize {
+        (self.0 >> (9 * level)) & 511
     }
 }
 
+// Physical memory is accessed through the direct map.
 impl PhysAddr {
     pub fn get_ref<T>(&self) -> &'static T {
-        unsafe { (self.0 as *const T).as_ref().unwrap() }
+        unsafe { (phys_to_virt(self.0) as *const T).as_ref().unwrap() }
     }
     pub fn get_mut<T>(&self) -> &'static mut T {
-        unsafe { (self.0 as *mut T).as_mut().unwrap() }
+        unsafe { (phys_to_virt(self.0) as *mut T).as_mut().unwrap() }
     }
 }
 impl PhysPageNum {
     pub fn get_pte_array(&self) -> &'static mut [PageTableEntry] {
         let pa: PhysAddr = (*self).into();
-        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut PageTableEntry, 512) }
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(pa.0) as *mut PageTableEntry, 512) }
     }
     pub fn get_bytes_array(&self) -> &'static mut [u8] {
         let pa: PhysAddr = (*self).into();
-        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut u8, 4096) }
+        unsafe { core::slice::from_raw_parts_mut(phys_to_virt(pa.0) as *mut u8, 4096) }
     }
     pub fn get_mut<T>(&self) -> &'static mut T {
         let pa: PhysAddr = (*self).into();
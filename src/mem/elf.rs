@@ -0,0 +1,326 @@
+//! Checking ELF executables before `MemorySet::from_elf` maps them.
+//!
+//! xmas_elf reads headers in place and slices the file wherever they
+//! point, panicking on anything out of range, so all of that is checked
+//! here first. Segments need not be page-aligned and may share pages, but
+//! must not load to the same bytes. `ET_DYN` files are moved up by a load
+//! bias, which puts their lowest page at the base they are loaded for.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use xmas_elf::header::{Class, Data, Machine, Type};
+use xmas_elf::program::{self, ProgramHeader64};
+use xmas_elf::ElfFile;
+use crate::config::PAGE_SIZE;
+use crate::mem::memory_set::MapPermission;
+
+const PT_GNU_STACK: u32 = 0x6474_e551;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// xmas_elf rejected the file header.
+    Parse(&'static str),
+    /// The file is not 8-byte aligned in memory, which xmas_elf needs.
+    Misaligned,
+    /// Not a little-endian 64-bit RISC-V file.
+    WrongArch,
+    /// Neither `ET_EXEC` nor `ET_DYN`.
+    NotExecutable,
+    /// The program headers or a segment's data run past the end of the file.
+    Truncated,
+    /// A segment that is malformed or doesn't fit in user space.
+    BadSegment,
+    /// Two segments load to the same bytes, or a segment to pages already in use.
+    Overlap,
+    NoSegments,
+    /// The entry point is not in an executable segment.
+    BadEntry,
+    /// A malformed `PT_INTERP`, or one in the interpreter itself.
+    BadInterp,
+}
+
+/// A `PT_LOAD` segment, with the load bias applied.
+pub struct Segment<'a> {
+    pub start: usize,
+    pub end: usize,
+    /// What goes at `start`; the rest up to `end` is zeroed.
+    pub data: &'a [u8],
+    pub perm: MapPermission,
+}
+
+pub struct Elf<'a> {
+    /// Sorted by address.
+    pub segments: Vec<Segment<'a>>,
+    pub bias: usize,
+    pub entry: usize,
+    /// Where the program headers are loaded, if they are.
+    pub phdr: Option<usize>,
+    pub phnum: usize,
+    pub interp: Option<String>,
+    /// `PT_GNU_STACK` asks for an executable stack.
+    pub exec_stack: bool,
+}
+
+/// Everything about a loaded program besides its address space, including
+/// what its auxiliary vector needs.
+#[derive(Debug, Clone)]
+pub struct ElfInfo {
+    /// Where to start: the interpreter's entry point once it is loaded,
+    /// else `entry`.
+    pub start: usize,
+    /// `AT_ENTRY`
+    pub entry: usize,
+    /// `AT_PHDR`
+    pub phdr: Option<usize>,
+    /// `AT_PHNUM`
+    pub phnum: usize,
+    /// `AT_BASE`: load bias of the interpreter, 0 without one.
+    pub interp_base: usize,
+    /// `PT_INTERP`, for `MemorySet::load_interpreter`.
+    pub interp: Option<String>,
+    /// Top of the user stack.
+    pub user_sp: usize,
+}
+
+/// Check `data` and find where its segments go, `ET_DYN` files from `dyn_base`.
+/// Segments must end by `user_end`, the end of the target's user space.
+pub fn parse(data: &[u8], dyn_base: usize, user_end: usize) -> Result<Elf<'_>, ElfError> {
+    if !(data.as_ptr() as usize).is_multiple_of(8) {
+        return Err(ElfError::Misaligned);
+    }
+    let elf = ElfFile::new(data).map_err(ElfError::Parse)?;
+    let header = &elf.header;
+    if header.pt1.class() != Class::SixtyFour
+        || header.pt1.data() != Data::LittleEndian
+        || header.pt2.machine().as_machine() != Machine::RISC_V
+    {
+        return Err(ElfError::WrongArch);
+    }
+    let is_dyn = match header.pt2.type_().as_type() {
+        Type::Executable => false,
+        Type::SharedObject => true,
+        _ => return Err(ElfError::NotExecutable),
+    };
+    let ph_offset = header.pt2.ph_offset() as usize;
+    let phnum = header.pt2.ph_count() as usize;
+    let ph_size = phnum * size_of::<ProgramHeader64>();
+    if header.pt2.ph_entry_size() as usize != size_of::<ProgramHeader64>() || !ph_offset.is_multiple_of(8) {
+        return Err(ElfError::Parse("bad program header table"));
+    }
+    if ph_offset.checked_add(ph_size).is_none_or(|end| end > data.len()) {
+        return Err(ElfError::Truncated);
+    }
+    let file_range = |offset: u64, size: u64| {
+        let start = offset as usize;
+        start
+            .checked_add(size as usize)
+            .filter(|&end| end <= data.len())
+            .map(|end| &data[start..end])
+            .ok_or(ElfError::Truncated)
+    };
+
+    let headers: Vec<_> = elf.program_iter().collect();
+    let loads = || headers.iter().filter(|ph| ph.get_type() == Ok(program::Type::Load) && ph.mem_size() > 0);
+    let lowest = loads().map(|ph| ph.virtual_addr() as usize).min().ok_or(ElfError::NoSegments)?;
+    let bias = if is_dyn {
+        dyn_base.wrapping_sub(lowest & !(PAGE_SIZE - 1))
+    } else {
+        0
+    };
+
+    let mut elf_info = Elf {
+        segments: Vec::new(),
+        bias,
+        entry: (header.pt2.entry_point() as usize).wrapping_add(bias),
+        phdr: None,
+        phnum,
+        interp: None,
+        exec_stack: false,
+    };
+    for ph in headers.iter() {
+        match ph.get_type() {
+            Ok(program::Type::Load) if ph.mem_size() > 0 => {
+                if ph.file_size() > ph.mem_size() {
+                    return Err(ElfError::BadSegment);
+                }
+                let start = (ph.virtual_addr() as usize).wrapping_add(bias);
+                let end = start
+                    .checked_add(ph.mem_size() as usize)
+                    .filter(|&end| end <= user_end)
+                    .ok_or(ElfError::BadSegment)?;
+                let mut perm = MapPermission::U;
+                if ph.flags().is_read() {
+                    perm |= MapPermission::R;
+                }
+                if ph.flags().is_write() {
+                    perm |= MapPermission::W;
+                }
+                if ph.flags().is_execute() {
+                    perm |= MapPermission::X;
+                }
+                let data = file_range(ph.offset(), ph.file_size())?;
+                // the program headers, when there is no PT_PHDR to say where
+                let in_file = ph.offset() as usize..ph.offset() as usize + data.len();
+                if elf_info.phdr.is_none() && in_file.contains(&ph_offset) && ph_offset + ph_size <= in_file.end {
+                    elf_info.phdr = Some(start + (ph_offset - in_file.start));
+                }
+                elf_info.segments.push(Segment { start, end, data, perm });
+            }
+            Ok(program::Type::Phdr) => {
+                elf_info.phdr = Some((ph.virtual_addr() as usize).wrapping_add(bias));
+            }
+            Ok(program::Type::Interp) => {
+                let path = file_range(ph.offset(), ph.file_size())?;
+                elf_info.interp = match path.split_last() {
+                    Some((0, path)) if elf_info.interp.is_none() && !path.is_empty() && !path.contains(&0) => {
+                        Some(String::from(core::str::from_utf8(path).map_err(|_| ElfError::BadInterp)?))
+                    }
+                    _ => return Err(ElfError::BadInterp),
+                };
+            }
+            Ok(program::Type::OsSpecific(PT_GNU_STACK)) => {
+                elf_info.exec_stack = ph.flags().is_execute();
+            }
+            _ => {}
+        }
+    }
+
+    elf_info.segments.sort_by_key(|segment| segment.start);
+    if elf_info.segments.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        return Err(ElfError::Overlap);
+    }
+    let entry = elf_info.entry;
+    if !elf_info
+        .segments
+        .iter()
+        .any(|segment| (segment.start..segment.end).contains(&entry) && segment.perm.contains(MapPermission::X))
+    {
+        return Err(ElfError::BadEntry);
+    }
+    Ok(elf_info)
+}
+
+/// A RISC-V ELF file with the given type and entry point, its program
+/// headers at 64 and `file_size` bytes in all; `phdrs` are (type, flags,
+/// offset, vaddr, filesz, memsz).
+fn test_elf(e_type: u16, entry: u64, phdrs: &[(u32, u32, u64, u64, u64, u64)], file_size: usize) -> Vec<u64> {
+    let mut bytes = alloc::vec![0u8; file_size];
+    let mut put = |offset: usize, value: &[u8]| bytes[offset..offset + value.len()].copy_from_slice(value);
+    put(0, &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+    put(16, &e_type.to_le_bytes());
+    put(18, &0xf3u16.to_le_bytes());
+    put(20, &1u32.to_le_bytes());
+    put(24, &entry.to_le_bytes());
+    put(32, &64u64.to_le_bytes());
+    put(52, &64u16.to_le_bytes());
+    put(54, &56u16.to_le_bytes());
+    put(56, &(phdrs.len() as u16).to_le_bytes());
+    for (i, &(p_type, flags, offset, vaddr, filesz, memsz)) in phdrs.iter().enumerate() {
+        let ph = 64 + i * 56;
+        put(ph, &p_type.to_le_bytes());
+        put(ph + 4, &flags.to_le_bytes());
+        put(ph + 8, &offset.to_le_bytes());
+        put(ph + 16, &vaddr.to_le_bytes());
+        put(ph + 32, &filesz.to_le_bytes());
+        put(ph + 40, &memsz.to_le_bytes());
+        put(ph + 48, &(PAGE_SIZE as u64).to_le_bytes());
+    }
+    // keep it 8-byte aligned for xmas_elf
+    bytes.chunks(8).map(|chunk| {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(word)
+    }).collect()
+}
+
+fn as_bytes(words: &[u64], len: usize) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(words.as_ptr() as *const u8, len) }
+}
+
+#[allow(unused)]
+pub fn elf_loader_test() {
+    use crate::config::{ELF_ET_DYN_BASE, ELF_INTERP_BASE, USER_STACK_SIZE};
+    use crate::mem::address::VirtAddr;
+    use crate::mem::memory_set::MemorySet;
+    use crate::mem::page_table::PTEFlags;
+    use crate::println;
+    const PT_LOAD: u32 = 1;
+    const PT_INTERP: u32 = 3;
+    const PT_PHDR: u32 = 6;
+    const X: u32 = 1;
+    const W: u32 = 2;
+    const R: u32 = 4;
+    // a PIE whose data starts in the page its text ends in, then runs on in .bss
+    let interp = b"/lib/ld.so\0";
+    let size = 0x1020;
+    let mut program = test_elf(3, 0x1000, &[
+        (PT_PHDR, R, 64, 64, 5 * 56, 5 * 56),
+        (PT_INTERP, R, 0x1010, 0x1010, interp.len() as u64, interp.len() as u64),
+        (PT_LOAD, R | X, 0, 0, 0x1008, 0x1008),
+        (PT_LOAD, R | W, 0x1008, 0x1008, 0x8, 0x2000),
+        (PT_GNU_STACK, R | W, 0, 0, 0, 0),
+    ], size);
+    let bytes = unsafe { core::slice::from_raw_parts_mut(program.as_mut_ptr() as *mut u8, size) };
+    bytes[0x1000..0x1008].copy_from_slice(&0x0000_0013u64.to_le_bytes());
+    bytes[0x1008..0x1010].copy_from_slice(&0x1234_5678u64.to_le_bytes());
+    bytes[0x1010..0x1010 + interp.len()].copy_from_slice(interp);
+    let (mut memory_set, mut info) = MemorySet::from_elf(as_bytes(&program, size)).unwrap();
+    assert_eq!(info.entry, ELF_ET_DYN_BASE + 0x1000);
+    assert_eq!(info.start, info.entry);
+    assert_eq!((info.phdr, info.phnum), (Some(ELF_ET_DYN_BASE + 64), 5));
+    assert_eq!(info.interp.as_deref(), Some("/lib/ld.so"));
+    let flags = |memory_set: &MemorySet, va: usize| memory_set.translate(VirtAddr::from(va).floor()).unwrap().flags();
+    let read = |memory_set: &MemorySet, va: usize| {
+        let pte = memory_set.translate(VirtAddr::from(va).floor()).unwrap();
+        let offset = VirtAddr::from(va).page_offset();
+        u64::from_le_bytes(pte.ppn().get_bytes_array()[offset..offset + 8].try_into().unwrap())
+    };
+    // the shared page has both segments' permissions, the rest only their own
+    assert!(flags(&memory_set, ELF_ET_DYN_BASE + 0x1000).contains(PTEFlags::R | PTEFlags::W | PTEFlags::X));
+    assert!(!flags(&memory_set, ELF_ET_DYN_BASE).contains(PTEFlags::W));
+    assert!(!flags(&memory_set, ELF_ET_DYN_BASE + 0x2000).contains(PTEFlags::X));
+    assert_eq!(read(&memory_set, ELF_ET_DYN_BASE + 0x1000), 0x13);
+    assert_eq!(read(&memory_set, ELF_ET_DYN_BASE + 0x1008), 0x1234_5678);
+    // .bss is zeroed, not filled from the rest of the file, here PT_INTERP
+    assert_eq!(read(&memory_set, ELF_ET_DYN_BASE + 0x1010), 0);
+    // the stack is above a guard page and not executable
+    let guard = info.user_sp - USER_STACK_SIZE - PAGE_SIZE;
+    assert_eq!(guard, ELF_ET_DYN_BASE + 0x4000);
+    assert!(memory_set.translate(VirtAddr::from(guard).floor()).is_none_or(|pte| !pte.is_valid()));
+    let stack_flags = flags(&memory_set, info.user_sp - 1);
+    assert!(stack_flags.contains(PTEFlags::U | PTEFlags::W) && !stack_flags.contains(PTEFlags::X));
+
+    // the interpreter goes at its own base and is where the program starts
+    let interp_size = 0x1008;
+    let ld_so = test_elf(3, 0x1000, &[(PT_LOAD, R | X, 0, 0, 0x1008, 0x1008)], interp_size);
+    memory_set.load_interpreter(&mut info, as_bytes(&ld_so, interp_size)).unwrap();
+    assert_eq!((info.interp_base, info.start), (ELF_INTERP_BASE, ELF_INTERP_BASE + 0x1000));
+    assert_eq!(memory_set.load_interpreter(&mut info, as_bytes(&ld_so, interp_size)).err(), Some(ElfError::Overlap));
+    assert_eq!(
+        MemorySet::from_elf(as_bytes(&program, size)).and_then(|(mut set, mut info)| set.load_interpreter(&mut info, as_bytes(&program, size))).err(),
+        Some(ElfError::BadInterp)
+    );
+
+    // broken files are refused, not panicked on
+    let load = |elf: &[u64], size: usize| MemorySet::from_elf(as_bytes(elf, size)).err();
+    assert!(matches!(load(&program, 60), Some(ElfError::Parse(_))));
+    assert_eq!(load(&program, 0x100), Some(ElfError::Truncated));
+    assert_eq!(MemorySet::from_elf(&as_bytes(&program, size)[1..]).err(), Some(ElfError::Misaligned));
+    let overlapping = test_elf(2, 0x1000, &[(PT_LOAD, R | X, 0, 0x1000, 0x10, 0x10), (PT_LOAD, R | W, 0, 0x100f, 0x10, 0x10)], 0x100);
+    assert_eq!(load(&overlapping, 0x100), Some(ElfError::Overlap));
+    let past_end = test_elf(2, 0x1000, &[(PT_LOAD, R | X, 0x80, 0x1000, 0x100, 0x100)], 0x100);
+    assert_eq!(load(&past_end, 0x100), Some(ElfError::Truncated));
+    let bad_entry = test_elf(2, 0x3000, &[(PT_LOAD, R | X, 0, 0x1000, 0x10, 0x10)], 0x100);
+    assert_eq!(load(&bad_entry, 0x100), Some(ElfError::BadEntry));
+    let kernel_half = test_elf(2, 0x1000, &[(PT_LOAD, R | X, 0, usize::MAX as u64 - 0xfff, 0x10, 0x10)], 0x100);
+    assert_eq!(load(&kernel_half, 0x100), Some(ElfError::BadSegment));
+    // how much is user space depends on the paging mode
+    let above_sv39 = test_elf(2, 1 << 38, &[(PT_LOAD, R | X, 0, 1 << 38, 0x10, 0x10)], 0x100);
+    assert_eq!(parse(as_bytes(&above_sv39, 0x100), 0, 1 << 38).err(), Some(ElfError::BadSegment));
+    assert!(parse(as_bytes(&above_sv39, 0x100), 0, 1 << 47).is_ok());
+    let mut x86 = test_elf(2, 0x1000, &[(PT_LOAD, R | X, 0, 0x1000, 0x10, 0x10)], 0x100);
+    x86[2] = 0x1_003e_0002;
+    assert_eq!(load(&x86, 0x100), Some(ElfError::WrongArch));
+    println!("elf_loader_test passed!");
+}
@@ -1,44 +1,155 @@
+use alloc::vec;
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use crate::config::{MEMORY_END, PAGE_SIZE, PAGE_SIZE_BITS};
-use crate::mem::address::{PhysAddr, PhysPageNum};
+use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
+use crate::mem::address::{phys_to_virt, virt_to_phys, PhysAddr, PhysPageNum};
+use crate::platform::PLATFORM;
 use crate::println;
-use crate::sync::UPSafeCell;
-
-trait FrameAllocator {
+use crate::sync::SpinLockIrq;
+
+/// Physical frame allocators. The kernel heap grows by taking frames from
+/// here while holding its own lock, so an implementation must not allocate
+/// from the heap except in `init`. That runs before the allocator is
+/// installed in `FRAME_ALLOCATOR`, so the heap cannot grow yet either:
+/// what `init` allocates has to fit in the boot heap.
+pub trait FrameAllocator {
     fn new() -> Self;
+    /// Manage the frames in [l, r).
+    fn init(&mut self, l: PhysPageNum, r: PhysPageNum);
     fn alloc(&mut self) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    /// Allocate `1 << order` contiguous frames, aligned to `1 << order` frames.
+    /// Allocators that only deal in single frames return `None` for `order > 0`.
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        if order == 0 { self.alloc() } else { None }
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        assert_eq!(order, 0, "{} has no contiguous allocations", core::any::type_name::<Self>());
+        self.dealloc(ppn);
+    }
+    fn free_frames(&self) -> usize;
+    fn total_frames(&self) -> usize;
 }
 
 static VANITY_MAGIC_NUMBER: usize = 0xdeadbeef;
 
 pub struct LinkedListFrameAllocator {
     range: (PhysPageNum, PhysPageNum),
-    head: usize
+    head: usize,
+    free: usize,
 }
 
+// Chosen by cargo feature; the buddy allocator unless another one is asked for.
+#[cfg(feature = "frame-alloc-stack")]
 type FrameAllocatorImpl = StackFrameAllocator;
+#[cfg(all(feature = "frame-alloc-list", not(feature = "frame-alloc-stack")))]
+type FrameAllocatorImpl = LinkedListFrameAllocator;
+#[cfg(all(
+    feature = "frame-alloc-bitmap",
+    not(any(feature = "frame-alloc-stack", feature = "frame-alloc-list"))
+))]
+type FrameAllocatorImpl = BitmapFrameAllocator;
+#[cfg(not(any(
+    feature = "frame-alloc-stack",
+    feature = "frame-alloc-list",
+    feature = "frame-alloc-bitmap"
+)))]
+type FrameAllocatorImpl = BuddyFrameAllocator;
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> = unsafe {
-        UPSafeCell::new(FrameAllocatorImpl::new())
-    };
+    /// Masks interrupts, as handlers that allocate may grow the heap.
+    pub static ref FRAME_ALLOCATOR: SpinLockIrq<FrameAllocatorImpl> =
+        SpinLockIrq::new(FrameAllocatorImpl::new()).named("FRAME_ALLOCATOR");
 }
 
 pub fn init_frame_allocator() {
-    extern "C" {
+    unsafe extern "C" {
         fn ekernel();
     }
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .init(PhysAddr::from(ekernel as usize).ceil(), PhysAddr::from(MEMORY_END).floor());
+    // The allocators manage a single range, so take the largest free one
+    let (start, end) = PLATFORM
+        .read()
+        .free_ranges(virt_to_phys(ekernel as usize))
+        .into_iter()
+        .max_by_key(|(start, end)| end - start)
+        .expect("No free physical memory");
+    // Not under FRAME_ALLOCATOR, which growing the heap takes. Nor can
+    // growing help `init`: until the allocator is installed below, there
+    // are no frames to grow with, so its metadata must fit in the boot heap.
+    let mut allocator = FrameAllocatorImpl::new();
+    allocator.init(PhysAddr::from(start).ceil(), PhysAddr::from(end).floor());
+    let frames = allocator.total_frames();
+    // the one replaced is from `new`, with nothing on the heap to free
+    *FRAME_ALLOCATOR.exclusive_access() = allocator;
+    println!(
+        "[kernel] frame allocator: {}, {} frames",
+        core::any::type_name::<FrameAllocatorImpl>().rsplit("::").next().unwrap(),
+        frames
+    );
+}
+
+/// What a frame is used for, for `meminfo`.
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameOwner {
+    PageTable,
+    UserData,
+    KernelStack,
+    Heap,
+    Slab,
+    Other,
+}
+
+impl FrameOwner {
+    pub const COUNT: usize = 6;
+    pub const ALL: [FrameOwner; Self::COUNT] = [
+        Self::PageTable,
+        Self::UserData,
+        Self::KernelStack,
+        Self::Heap,
+        Self::Slab,
+        Self::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PageTable => "page table",
+            Self::UserData => "user data",
+            Self::KernelStack => "kernel stack",
+            Self::Heap => "heap",
+            Self::Slab => "slab",
+            Self::Other => "other",
+        }
+    }
+
+    fn account(self, frames: usize, allocated: bool) {
+        let counter = &FRAMES_OWNED[self as usize];
+        if allocated {
+            counter.fetch_add(frames, Ordering::Relaxed);
+        } else {
+            counter.fetch_sub(frames, Ordering::Relaxed);
+        }
+    }
 }
 
-pub fn frame_alloc() -> Option<FrameTracker> {
+/// Frames held by each owner, indexed by `FrameOwner as usize`.
+static FRAMES_OWNED: [AtomicUsize; FrameOwner::COUNT] = [const { AtomicUsize::new(0) }; FrameOwner::COUNT];
+
+pub fn frames_owned(owner: FrameOwner) -> usize {
+    FRAMES_OWNED[owner as usize].load(Ordering::Relaxed)
+}
+
+/// (free, total) frames of the frame allocator.
+pub fn frame_counts() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.exclusive_access();
+    (allocator.free_frames(), allocator.total_frames())
+}
+
+pub fn frame_alloc(owner: FrameOwner) -> Option<FrameTracker> {
     FRAME_ALLOCATOR
         .exclusive_access()
         .alloc()
-        .map(|ppn| FrameTracker::new(ppn))
+        .map(|ppn| FrameTracker::new(ppn, owner))
 }
 
 fn frame_dealloc(ppn: PhysPageNum) {
@@ -47,35 +158,93 @@ fn frame_dealloc(ppn: PhysPageNum) {
         .dealloc(ppn);
 }
 
+/// Allocate `1 << order` physically contiguous frames, aligned to their size.
+pub fn frame_alloc_contiguous(order: usize, owner: FrameOwner) -> Option<ContiguousFrames> {
+    FRAME_ALLOCATOR
+        .exclusive_access()
+        .alloc_contiguous(order)
+        .map(|ppn| ContiguousFrames::new(ppn, order, owner))
+}
+
+#[derive(Debug)]
 pub struct FrameTracker {
     pub ppn: PhysPageNum,
+    pub owner: FrameOwner,
 }
 
 impl FrameTracker {
-    pub fn new(ppn: PhysPageNum) -> Self {
+    pub fn new(ppn: PhysPageNum, owner: FrameOwner) -> Self {
         // page cleaning
         let bytes_array = ppn.get_bytes_array();
         for i in bytes_array {
             *i = 0;
         }
-        Self { ppn }
+        owner.account(1, true);
+        Self { ppn, owner }
     }
 }
 
 impl Drop for FrameTracker {
     fn drop(&mut self) {
+        self.owner.account(1, false);
         frame_dealloc(self.ppn);
     }
 }
 
+/// `1 << order` contiguous frames starting at `ppn`, zeroed, freed on drop.
+pub struct ContiguousFrames {
+    pub ppn: PhysPageNum,
+    pub order: usize,
+    pub owner: FrameOwner,
+}
+
+impl ContiguousFrames {
+    fn new(ppn: PhysPageNum, order: usize, owner: FrameOwner) -> Self {
+        for i in 0..1 << order {
+            PhysPageNum(ppn.0 + i).get_bytes_array().fill(0);
+        }
+        owner.account(1 << order, true);
+        Self { ppn, order, owner }
+    }
+
+    pub fn pages(&self) -> usize {
+        1 << self.order
+    }
+}
+
+impl Drop for ContiguousFrames {
+    fn drop(&mut self) {
+        self.owner.account(1 << self.order, false);
+        FRAME_ALLOCATOR
+            .exclusive_access()
+            .dealloc_contiguous(self.ppn, self.order);
+    }
+}
+
 impl LinkedListFrameAllocator {
+    // Helper to check if a PPN is within our valid range
+    fn is_valid_ppn(&self, ppn: PhysPageNum) -> bool {
+        ppn.0 >= self.range.0.0 && ppn.0 < self.range.1.0
+    }
+}
+
+impl FrameAllocator for LinkedListFrameAllocator {
+    fn new() -> Self {
+        Self {
+            head: 0, // invalid until initialized
+            range: (PhysPageNum(0), PhysPageNum(0)), // invalid range
+            free: 0,
+        }
+    }
+
     // Initialize the free list with pages in [l, r)
-    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+    fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
         assert!(l.0 > 0, "Invalid starting physical page number");
         assert!(l.0 < r.0, "Invalid physical page range");
 
         // Store the valid range
         self.range = (l, r);
+        self.free = r.0 - l.0;
 
         // Create a linked list of free pages
         self.head = 0;
@@ -85,7 +254,7 @@ impl LinkedListFrameAllocator {
         let mut current = r.0;
         while current > l.0 {
             current -= 1;
-            let frame_address = current << PAGE_SIZE_BITS;
+            let frame_address = phys_to_virt(current << PAGE_SIZE_BITS);
 
             // Store next pointer at start of page
             unsafe {
@@ -102,27 +271,13 @@ impl LinkedListFrameAllocator {
         }
     }
 
-    // Helper to check if a PPN is within our valid range
-    fn is_valid_ppn(&self, ppn: PhysPageNum) -> bool {
-        ppn.0 >= self.range.0.0 && ppn.0 < self.range.1.0
-    }
-}
-
-impl FrameAllocator for LinkedListFrameAllocator {
-    fn new() -> Self {
-        Self {
-            head: 0, // invalid until initialized
-            range: (PhysPageNum(0), PhysPageNum(0)), // invalid range
-        }
-    }
-
     fn alloc(&mut self) -> Option<PhysPageNum> {
         if self.head == 0 {
             return None; // No free frames
         }
 
         let allocated_ppn = PhysPageNum(self.head);
-        let frame_address = self.head << PAGE_SIZE_BITS;
+        let frame_address = phys_to_virt(self.head << PAGE_SIZE_BITS);
 
         // Read the next pointer
         unsafe {
@@ -139,6 +294,7 @@ impl FrameAllocator for LinkedListFrameAllocator {
             core::ptr::write_bytes(page_ptr, 0, PAGE_SIZE);
         }
 
+        self.free -= 1;
         Some(allocated_ppn)
     }
 
@@ -147,7 +303,7 @@ impl FrameAllocator for LinkedListFrameAllocator {
         assert!(ppn.0 > 0, "Attempting to deallocate invalid PPN 0");
         assert!(self.is_valid_ppn(ppn), "PPN outside valid memory range");
 
-        let frame_address = ppn.0 << PAGE_SIZE_BITS;
+        let frame_address = phys_to_virt(ppn.0 << PAGE_SIZE_BITS);
 
         // Check for double free by looking at the magic number position
         unsafe {
@@ -163,25 +319,58 @@ impl FrameAllocator for LinkedListFrameAllocator {
 
         // Update head to point to this newly freed frame
         self.head = ppn.0;
+        self.free += 1;
+    }
+
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    fn total_frames(&self) -> usize {
+        self.range.1.0 - self.range.0.0
     }
 }
 
+/// Hands out frames in order, then the freed ones, last freed first. The
+/// stack of freed frames is threaded through the frames themselves, so
+/// it takes no heap however much memory there is.
 pub struct StackFrameAllocator {
+    start: usize,
     current: usize,
     end: usize,
-    recycled: Vec<usize>,
+    /// top of the freed frames, 0 if none
+    recycled: usize,
+    recycled_count: usize,
+}
+
+impl StackFrameAllocator {
+    /// The freed frame below `ppn` on the stack, kept in its first word.
+    fn next_recycled(ppn: usize) -> &'static mut usize {
+        PhysPageNum(ppn).get_mut()
+    }
 }
 
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
         Self {
+            start: 0,
             current: 0,
             end: 0,
-            recycled: Vec::new(),
+            recycled: 0,
+            recycled_count: 0,
         }
     }
+    fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        assert!(l.0 > 0, "Invalid starting physical page number");
+        self.start = l.0;
+        self.current = l.0;
+        self.end = r.0;
+    }
     fn alloc(&mut self) -> Option<PhysPageNum> {
-        if let Some(ppn) = self.recycled.pop() {
+        if self.recycled != 0 {
+            let ppn = self.recycled;
+            self.recycled = *Self::next_recycled(ppn);
+            self.recycled_count -= 1;
             Some(ppn.into())
         } else {
             if self.current == self.end {
@@ -195,21 +384,278 @@ impl FrameAllocator for StackFrameAllocator {
     fn dealloc(&mut self, ppn: PhysPageNum) {
         let ppn = ppn.0;
         // validity check
-        if ppn >= self.current || self.recycled
-            .iter()
-            .find(|&v| {*v == ppn})
-            .is_some() {
+        let mut freed = self.recycled;
+        while freed != 0 {
+            if freed == ppn {
+                break;
+            }
+            freed = *Self::next_recycled(freed);
+        }
+        if ppn < self.start || ppn >= self.current || freed != 0 {
             panic!("Frame ppn={:#x} has not been allocated!", ppn);
         }
         // recycle
-        self.recycled.push(ppn);
+        *Self::next_recycled(ppn) = self.recycled;
+        self.recycled = ppn;
+        self.recycled_count += 1;
+    }
+    fn free_frames(&self) -> usize {
+        self.end - self.current + self.recycled_count
+    }
+    fn total_frames(&self) -> usize {
+        self.end - self.start
     }
 }
 
-impl StackFrameAllocator {
-    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
-        self.current = l.0;
+/// Largest block the buddy allocator hands out: 2^18 frames = 1 GiB.
+pub const MAX_ORDER: usize = 18;
+
+const NO_BLOCK: usize = usize::MAX;
+
+/// Links of a free block, kept in its first frame.
+struct FreeBlock {
+    next: usize,
+    prev: usize,
+}
+
+/// Binary buddy allocator. A block of order `k` is `1 << k` frames whose
+/// first PPN is a multiple of `1 << k`, and its buddy is the block at
+/// `ppn ^ (1 << k)`. Free blocks sit on per-order doubly linked lists
+/// threaded through the frames themselves, so merging with a free buddy
+/// on `dealloc` is O(1) per order and O(log n) overall.
+pub struct BuddyFrameAllocator {
+    start: usize,
+    end: usize,
+    free: usize,
+    free_lists: [usize; MAX_ORDER + 1],
+    /// `order + 1` for the first frame of each free block, 0 elsewhere
+    free_order: Vec<u8>,
+}
+
+impl BuddyFrameAllocator {
+    fn block(ppn: usize) -> &'static mut FreeBlock {
+        PhysPageNum(ppn).get_mut()
+    }
+
+    fn is_free(&self, ppn: usize, order: usize) -> bool {
+        self.free_order[ppn - self.start] as usize == order + 1
+    }
+
+    fn push(&mut self, ppn: usize, order: usize) {
+        let head = self.free_lists[order];
+        *Self::block(ppn) = FreeBlock { next: head, prev: NO_BLOCK };
+        if head != NO_BLOCK {
+            Self::block(head).prev = ppn;
+        }
+        self.free_lists[order] = ppn;
+        self.free_order[ppn - self.start] = order as u8 + 1;
+    }
+
+    fn remove(&mut self, ppn: usize, order: usize) {
+        let FreeBlock { next, prev } = *Self::block(ppn);
+        if prev == NO_BLOCK {
+            self.free_lists[order] = next;
+        } else {
+            Self::block(prev).next = next;
+        }
+        if next != NO_BLOCK {
+            Self::block(next).prev = prev;
+        }
+        self.free_order[ppn - self.start] = 0;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysPageNum> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NO_BLOCK)?;
+        let ppn = self.free_lists[found];
+        self.remove(ppn, found);
+        // give back the upper halves we don't need
+        for o in (order..found).rev() {
+            self.push(ppn + (1 << o), o);
+        }
+        Some(PhysPageNum(ppn))
+    }
+
+    /// Free a block of `order`, merging it with its buddies.
+    fn merge(&mut self, ppn: PhysPageNum, order: usize) {
+        let mut ppn = ppn.0;
+        assert!(
+            ppn >= self.start && ppn + (1 << order) <= self.end && ppn.is_multiple_of(1 << order),
+            "Frame ppn={:#x} order {} was not allocated here!", ppn, order
+        );
+        // a double free lands inside a free block of some order
+        for o in 0..=MAX_ORDER {
+            let head = ppn & !((1 << o) - 1);
+            if head >= self.start && self.is_free(head, o) {
+                panic!("Frame ppn={:#x} order {} has not been allocated!", ppn, order);
+            }
+        }
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            if buddy < self.start || buddy + (1 << order) > self.end || !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            ppn = ppn.min(buddy);
+            order += 1;
+        }
+        self.push(ppn, order);
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            free: 0,
+            free_lists: [NO_BLOCK; MAX_ORDER + 1],
+            free_order: Vec::new(),
+        }
+    }
+    fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        assert!(l.0 < r.0, "Invalid physical page range");
+        self.start = l.0;
+        self.end = r.0;
+        self.free = r.0 - l.0;
+        self.free_lists = [NO_BLOCK; MAX_ORDER + 1];
+        self.free_order = vec![0; r.0 - l.0];
+        // carve the range into the largest aligned blocks that fit
+        let mut ppn = l.0;
+        while ppn < r.0 {
+            let mut order = (ppn.trailing_zeros() as usize).min(MAX_ORDER);
+            while ppn + (1 << order) > r.0 {
+                order -= 1;
+            }
+            self.push(ppn, order);
+            ppn += 1 << order;
+        }
+    }
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        self.alloc_contiguous(0)
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        self.dealloc_contiguous(ppn, 0);
+    }
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        let ppn = self.pop(order)?;
+        self.free -= 1 << order;
+        Some(ppn)
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        self.merge(ppn, order);
+        self.free += 1 << order;
+    }
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+    fn total_frames(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// One bit per frame, set while the frame is allocated. Allocation scans
+/// for a clear bit (or an aligned run of them) from where the last one
+/// ended; freeing is O(1) and catches double frees exactly.
+pub struct BitmapFrameAllocator {
+    start: usize,
+    end: usize,
+    free: usize,
+    bits: Vec<u64>,
+    /// frame index to resume scanning from
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    fn is_allocated(&self, index: usize) -> bool {
+        self.bits[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize, allocated: bool) {
+        if allocated {
+            self.bits[index / 64] |= 1 << (index % 64);
+        } else {
+            self.bits[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    fn frames(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn new() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            free: 0,
+            bits: Vec::new(),
+            next: 0,
+        }
+    }
+    fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        assert!(l.0 < r.0, "Invalid physical page range");
+        self.start = l.0;
         self.end = r.0;
+        self.free = r.0 - l.0;
+        self.bits = vec![0; (r.0 - l.0).div_ceil(64)];
+        self.next = 0;
+    }
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        let frames = self.frames();
+        let (next, words) = (self.next, self.bits.len());
+        // whole words at a time, starting at the word holding `next`
+        for i in 0..=words {
+            let word = (next / 64 + i) % words;
+            if self.bits[word] == u64::MAX {
+                continue;
+            }
+            let index = word * 64 + self.bits[word].trailing_ones() as usize;
+            if index < frames {
+                self.set(index, true);
+                self.next = index + 1;
+                self.free -= 1;
+                return Some(PhysPageNum(self.start + index));
+            }
+        }
+        None
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        let ppn = ppn.0;
+        if ppn < self.start || ppn >= self.end || !self.is_allocated(ppn - self.start) {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn);
+        }
+        self.set(ppn - self.start, false);
+        self.free += 1;
+    }
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PhysPageNum> {
+        let count = 1 << order;
+        // first aligned run of free frames, by physical address
+        let mut ppn = self.start.next_multiple_of(count);
+        while ppn + count <= self.end {
+            let base = ppn - self.start;
+            match (0..count).rev().find(|&i| self.is_allocated(base + i)) {
+                Some(i) => ppn = (ppn + i + 1).next_multiple_of(count),
+                None => {
+                    (0..count).for_each(|i| self.set(base + i, true));
+                    self.free -= count;
+                    return Some(PhysPageNum(ppn));
+                }
+            }
+        }
+        None
+    }
+    fn dealloc_contiguous(&mut self, ppn: PhysPageNum, order: usize) {
+        for i in 0..1 << order {
+            self.dealloc(PhysPageNum(ppn.0 + i));
+        }
+    }
+    fn free_frames(&self) -> usize {
+        self.free
+    }
+    fn total_frames(&self) -> usize {
+        self.frames()
     }
 }
 
@@ -217,16 +663,40 @@ impl StackFrameAllocator {
 pub fn frame_allocator_test() {
     let mut v: Vec<FrameTracker> = Vec::new();
     for i in 0..5 {
-        let frame = frame_alloc().unwrap();
+        let frame = frame_alloc(FrameOwner::Other).unwrap();
         println!("{:?}", frame);
         v.push(frame);
     }
     v.clear();
     for i in 0..5 {
-        let frame = frame_alloc().unwrap();
+        let frame = frame_alloc(FrameOwner::Other).unwrap();
         println!("{:?}", frame);
         v.push(frame);
     }
     drop(v);
     println!("frame_allocator_test passed!");
-}
\ No newline at end of file
+}
+
+#[allow(unused)]
+pub fn frame_alloc_contiguous_test() {
+    let free = FRAME_ALLOCATOR.exclusive_access().free_frames();
+    let owned = frames_owned(FrameOwner::Other);
+    let Some(block) = frame_alloc_contiguous(4, FrameOwner::Other) else {
+        println!("frame_alloc_contiguous_test skipped: no contiguous allocations");
+        return;
+    };
+    assert_eq!(FRAME_ALLOCATOR.exclusive_access().free_frames(), free - 16);
+    assert_eq!(block.ppn.0 % 16, 0, "order-4 block is not aligned");
+    let single = frame_alloc(FrameOwner::Other).unwrap();
+    assert!(single.ppn.0 < block.ppn.0 || single.ppn.0 >= block.ppn.0 + block.pages());
+    assert_eq!(frames_owned(FrameOwner::Other), owned + 17);
+    let ppn = block.ppn;
+    drop(block);
+    drop(single);
+    // everything merged back, so the same block is found again
+    let again = frame_alloc_contiguous(4, FrameOwner::Other).unwrap();
+    assert_eq!(again.ppn, ppn);
+    drop(again);
+    assert_eq!(FRAME_ALLOCATOR.exclusive_access().free_frames(), free);
+    println!("frame_alloc_contiguous_test passed!");
+}
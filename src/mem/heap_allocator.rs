@@ -1,10 +1,176 @@
 
-use buddy_system_allocator::LockedHeap;
-use crate::config::KERNEL_HEAP_SIZE;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use buddy_system_allocator::{Heap, LockedHeap};
+use crate::config::{KERNEL_HEAP_SIZE, PAGE_SIZE};
 use crate::println;
+use crate::sync::{intr_masking_enter, intr_masking_exit};
+use super::frame_allocator::{frame_alloc_contiguous, FrameOwner};
+#[cfg(feature = "heap-debug")]
+use super::heap_debug;
+
+/// Grow the heap by at least this many frames at a time (as an order).
+const HEAP_GROW_ORDER: usize = 6;
+
+/// `LockedHeap` plus the high-water mark, which it doesn't keep.
+///
+/// The heap starts out as the `.bss.heap` region and grows with frames
+/// from the frame allocator whenever an allocation doesn't fit. Frames
+/// given to the heap stay there: the buddy heap can't tell when a whole
+/// region is free again.
+struct KernelHeap {
+    heap: LockedHeap,
+    /// most bytes ever allocated at once, including buddy rounding
+    peak: AtomicUsize,
+}
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap {
+    heap: LockedHeap::empty(),
+    peak: AtomicUsize::new(0),
+};
+
+impl KernelHeap {
+    /// Run `f` on the locked heap with interrupts masked: interrupt
+    /// handlers allocate too, and would spin on the lock this hart holds.
+    fn with_heap<R>(&self, f: impl FnOnce(&mut Heap) -> R) -> R {
+        intr_masking_enter();
+        let ret = f(&mut self.heap.lock());
+        intr_masking_exit();
+        ret
+    }
+
+    fn alloc_locked(&self, heap: &mut Heap, layout: Layout) -> *mut u8 {
+        let mut result = heap.alloc(layout);
+        if result.is_err() && grow(heap, layout) {
+            result = heap.alloc(layout);
+        }
+        match result {
+            Ok(ptr) => {
+                self.peak.fetch_max(heap.stats_alloc_actual(), Ordering::Relaxed);
+                ptr.as_ptr()
+            }
+            Err(_) => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc_locked(heap: &mut Heap, ptr: *mut u8, layout: Layout) {
+        unsafe { heap.dealloc(NonNull::new_unchecked(ptr), layout) }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| self.alloc_locked(heap, layout))
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_heap(|heap| unsafe { Self::dealloc_locked(heap, ptr, layout) })
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| heap_debug::alloc(heap, layout, |heap, layout| self.alloc_locked(heap, layout)))
+    }
+
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_heap(|heap| unsafe {
+            heap_debug::dealloc(heap, ptr, layout, |heap, ptr, layout| Self::dealloc_locked(heap, ptr, layout))
+        })
+    }
+}
+
+/// Print every live heap allocation with the call site that made it.
+#[cfg(feature = "heap-debug")]
+#[allow(unused)]
+pub fn dump_live_allocations() {
+    HEAP_ALLOCATOR.with_heap(heap_debug::dump);
+}
+
+/// Called at shutdown to report allocations that were never freed.
+#[cfg(feature = "heap-debug")]
+pub fn report_leaks() {
+    heap_debug::report_leaks(HEAP_ALLOCATOR.heap.try_lock().as_deref_mut());
+}
+
+/// Add frames from the frame allocator so that `layout` fits. Prefers
+/// `HEAP_GROW_ORDER` frames, settles for the least that will do.
+///
+/// This takes `FRAME_ALLOCATOR`, so it must never run under it: nothing
+/// may allocate from the heap while holding that lock.
+fn grow(heap: &mut Heap, layout: Layout) -> bool {
+    // the buddy heap rounds every request up to a power of two
+    let size = layout.size().next_power_of_two().max(layout.align());
+    let min_order = size.div_ceil(PAGE_SIZE).next_power_of_two().trailing_zeros() as usize;
+    for order in (min_order..=min_order.max(HEAP_GROW_ORDER)).rev() {
+        if let Some(frames) = frame_alloc_contiguous(order, FrameOwner::Heap) {
+            let start = frames.ppn.get_bytes_array().as_mut_ptr() as usize;
+            // owned by the heap from now on, still counted as heap frames
+            core::mem::forget(frames);
+            unsafe { heap.add_to_heap(start, start + (PAGE_SIZE << order)) };
+            return true;
+        }
+    }
+    false
+}
+
+/// Kernel heap usage, in bytes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub total: usize,
+    /// requested by callers
+    pub user: usize,
+    /// handed out, after rounding up to buddy blocks
+    pub allocated: usize,
+    pub peak: usize,
+    /// largest block a single allocation could still get
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    pub fn free(&self) -> usize {
+        self.total - self.allocated
+    }
+
+    /// Share of the free bytes that the largest free block can't serve, in percent.
+    pub fn fragmentation(&self) -> usize {
+        match self.free() {
+            0 => 0,
+            free => 100 - self.largest_free * 100 / free,
+        }
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    HEAP_ALLOCATOR.with_heap(stats)
+}
+
+fn stats(heap: &mut Heap) -> HeapStats {
+    // The free lists are private: probe for the largest block instead.
+    // Splitting one and freeing it merges everything back as it was.
+    let free = heap.stats_total_bytes() - heap.stats_alloc_actual();
+    let largest_free = (0..usize::BITS)
+        .rev()
+        .map(|order| 1usize << order)
+        .filter(|&size| size <= free)
+        .find(|&size| {
+            let layout = Layout::from_size_align(size, 1).unwrap();
+            heap.alloc(layout).map(|ptr| heap.dealloc(ptr, layout)).is_ok()
+        })
+        .unwrap_or(0);
+    HeapStats {
+        total: heap.stats_total_bytes(),
+        user: heap.stats_alloc_user(),
+        allocated: heap.stats_alloc_actual(),
+        peak: HEAP_ALLOCATOR.peak.load(Ordering::Relaxed),
+        largest_free,
+    }
+}
 
 unsafe extern "C" {
     fn kernel_heap_beg();
@@ -20,6 +186,7 @@ pub fn init_heap() {
             KERNEL_HEAP_SIZE, actual_heap_size
         );
         HEAP_ALLOCATOR
+            .heap
             .lock()
             .init(kernel_heap_beg as usize, KERNEL_HEAP_SIZE);
     }
@@ -43,10 +210,54 @@ pub fn heap_test() {
     for i in 0..500 {
         v.push(i);
     }
-    for i in 0..500 {
-        assert_eq!(v[i], i);
+    for (i, &value) in v.iter().enumerate() {
+        assert_eq!(value, i);
     }
     assert!(bss_range.contains(&(v.as_ptr() as usize)));
     drop(v);
     println!("heap_test passed!");
-}
\ No newline at end of file
+}
+#[allow(unused)]
+pub fn heap_grow_test() {
+    use alloc::vec::Vec;
+    use super::frame_allocator::frames_owned;
+    if frame_alloc_contiguous(1, FrameOwner::Other).is_none() {
+        println!("heap_grow_test skipped: no contiguous allocations");
+        return;
+    }
+    let before = heap_stats();
+    let frames = frames_owned(FrameOwner::Heap);
+    // more than the boot heap holds
+    let mut v: Vec<u8> = Vec::with_capacity(KERNEL_HEAP_SIZE);
+    v.resize(KERNEL_HEAP_SIZE, 0xa5);
+    assert!(v.iter().all(|&byte| byte == 0xa5));
+    assert!(heap_stats().total >= before.total + KERNEL_HEAP_SIZE);
+    assert!(frames_owned(FrameOwner::Heap) >= frames + KERNEL_HEAP_SIZE / PAGE_SIZE);
+    drop(v);
+    // the grown part stays in the heap and serves the next request
+    let total = heap_stats().total;
+    let v: Vec<u8> = Vec::with_capacity(KERNEL_HEAP_SIZE);
+    assert_eq!(heap_stats().total, total);
+    drop(v);
+    println!("heap_grow_test passed!");
+}
+
+#[cfg(feature = "heap-debug")]
+#[allow(unused)]
+pub fn heap_debug_test() {
+    use alloc::boxed::Box;
+    let (count, bytes) = HEAP_ALLOCATOR.with_heap(heap_debug::live_allocations);
+    let object = Box::new([0u64; 5]);
+    let ptr = object.as_ptr() as *const u8;
+    // guarded on both sides
+    unsafe {
+        assert!((1..=16).all(|i| *ptr.sub(i) == 0xbb));
+        assert!((0..16).all(|i| *ptr.add(40 + i) == 0xbb));
+    }
+    let live = HEAP_ALLOCATOR.with_heap(heap_debug::live_allocations);
+    assert_eq!(live, (count + 1, bytes + 40));
+    drop(object);
+    let live = HEAP_ALLOCATOR.with_heap(heap_debug::live_allocations);
+    assert_eq!(live, (count, bytes));
+    println!("heap_debug_test passed!");
+}
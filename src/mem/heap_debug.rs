@@ -0,0 +1,235 @@
+//! Kernel heap debugging, enabled with the `heap-debug` feature.
+//!
+//! Every allocation is wrapped in a block laid out as
+//!
+//!     [header][red zone][object][red zone]
+//!
+//! The header links all live allocations into a list and records their
+//! size and a short backtrace of return addresses, as linked rather than
+//! where KASLR put them (resolve them with `addr2line -e os`). Red zones
+//! are checked on free; objects are filled with `POISON_INUSE` when
+//! allocated and the whole block with `POISON_FREE` when freed, so reads
+//! of uninitialized or freed memory stand out. `dump_live_allocations`
+//! lists what is still allocated, and `report_leaks` does so at shutdown.
+//!
+//! Everything here runs under the heap lock, passed in as `&mut Heap`.
+
+use core::alloc::Layout;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+use buddy_system_allocator::Heap;
+use crate::config::BOOT_STACK_SIZE;
+use crate::mem::kaslr::link_address;
+use crate::{console, println};
+
+const POISON_INUSE: u8 = 0x5a;
+const POISON_FREE: u8 = 0x6b;
+const RED_ZONE: u8 = 0xbb;
+const RED_ZONE_SIZE: usize = 16;
+const BACKTRACE_DEPTH: usize = 6;
+const MAGIC: usize = 0x4845_4150_4442_4721;
+
+#[repr(C)]
+struct AllocHeader {
+    magic: usize,
+    size: usize,
+    align: usize,
+    serial: usize,
+    prev: *mut AllocHeader,
+    next: *mut AllocHeader,
+    /// return addresses, innermost first; 0 past the end
+    sites: [usize; BACKTRACE_DEPTH],
+}
+
+struct LiveList {
+    head: *mut AllocHeader,
+    count: usize,
+    bytes: usize,
+    serial: usize,
+}
+
+/// Only touched with the heap locked.
+struct Live(UnsafeCell<LiveList>);
+unsafe impl Sync for Live {}
+
+static LIVE: Live = Live(UnsafeCell::new(LiveList {
+    head: null_mut(),
+    count: 0,
+    bytes: 0,
+    serial: 0,
+}));
+
+fn live(_heap: &mut Heap) -> &mut LiveList {
+    unsafe { &mut *LIVE.0.get() }
+}
+
+/// Layout of the whole block for an object of `layout`, and where the object starts in it.
+fn block_layout(layout: Layout) -> (Layout, usize) {
+    let align = layout.align().max(align_of::<AllocHeader>());
+    let offset = (size_of::<AllocHeader>() + RED_ZONE_SIZE).next_multiple_of(align);
+    let size = offset + layout.size() + RED_ZONE_SIZE;
+    (Layout::from_size_align(size, align).unwrap(), offset)
+}
+
+/// Return addresses of the callers, following the frame pointers the
+/// kernel is built with. Stops where the chain leaves the current stack.
+#[inline(always)]
+fn backtrace() -> [usize; BACKTRACE_DEPTH] {
+    let mut sites = [0; BACKTRACE_DEPTH];
+    let mut fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    let stack = fp..fp + BOOT_STACK_SIZE;
+    for site in sites.iter_mut() {
+        if !stack.contains(&fp) || !fp.is_multiple_of(size_of::<usize>()) {
+            break;
+        }
+        let (ra, prev) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        *site = link_address(ra);
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+    sites
+}
+
+/// Allocate through `raw` with room for the header and red zones.
+pub fn alloc(heap: &mut Heap, layout: Layout, raw: impl FnOnce(&mut Heap, Layout) -> *mut u8) -> *mut u8 {
+    let (block_layout, offset) = block_layout(layout);
+    let block = raw(heap, block_layout);
+    if block.is_null() {
+        return block;
+    }
+    let live = live(heap);
+    let header = block as *mut AllocHeader;
+    live.serial += 1;
+    unsafe {
+        header.write(AllocHeader {
+            magic: MAGIC,
+            size: layout.size(),
+            align: layout.align(),
+            serial: live.serial,
+            prev: null_mut(),
+            next: live.head,
+            sites: backtrace(),
+        });
+        if !live.head.is_null() {
+            (*live.head).prev = header;
+        }
+    }
+    live.head = header;
+    live.count += 1;
+    live.bytes += layout.size();
+    unsafe {
+        let object = block.add(offset);
+        object.sub(RED_ZONE_SIZE).write_bytes(RED_ZONE, RED_ZONE_SIZE);
+        object.write_bytes(POISON_INUSE, layout.size());
+        object.add(layout.size()).write_bytes(RED_ZONE, RED_ZONE_SIZE);
+        object
+    }
+}
+
+/// Check and unlink the block around `ptr`, poison it, and free it through `raw`.
+///
+/// # Safety
+/// `ptr` and `layout` must come from `alloc`.
+pub unsafe fn dealloc(heap: &mut Heap, ptr: *mut u8, layout: Layout, raw: impl FnOnce(&mut Heap, *mut u8, Layout)) {
+    let (block_layout, offset) = block_layout(layout);
+    let block = unsafe { ptr.sub(offset) };
+    let header = unsafe { &mut *(block as *mut AllocHeader) };
+    if header.magic != MAGIC {
+        panic!("heap-debug: freeing {:p}, which is not allocated (double free?)", ptr);
+    }
+    if header.size != layout.size() || header.align != layout.align() {
+        panic!(
+            "heap-debug: {:p} freed as {:?}, allocated with size {} align {} at {:x?}",
+            ptr,
+            layout,
+            header.size,
+            header.align,
+            header.sites
+        );
+    }
+    let (before, after) = unsafe {
+        (
+            core::slice::from_raw_parts(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE),
+            core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE_SIZE),
+        )
+    };
+    if let Some(at) = before.iter().position(|&byte| byte != RED_ZONE) {
+        panic!(
+            "heap-debug: {} bytes before {:p} overwritten, allocated with size {} at {:x?}",
+            RED_ZONE_SIZE - at,
+            ptr,
+            header.size,
+            header.sites
+        );
+    }
+    if let Some(at) = after.iter().rposition(|&byte| byte != RED_ZONE) {
+        panic!(
+            "heap-debug: {} bytes after {:p} (size {}) overwritten, allocated at {:x?}",
+            at + 1,
+            ptr,
+            header.size,
+            header.sites
+        );
+    }
+    let live = live(heap);
+    unsafe {
+        if header.prev.is_null() {
+            live.head = header.next;
+        } else {
+            (*header.prev).next = header.next;
+        }
+        if !header.next.is_null() {
+            (*header.next).prev = header.prev;
+        }
+    }
+    live.count -= 1;
+    live.bytes -= layout.size();
+    unsafe { block.write_bytes(POISON_FREE, block_layout.size()) };
+    raw(heap, block, block_layout)
+}
+
+/// Number of live allocations and the bytes they asked for.
+pub fn live_allocations(heap: &mut Heap) -> (usize, usize) {
+    let live = live(heap);
+    (live.count, live.bytes)
+}
+
+/// Print every live allocation, newest first.
+pub fn dump(heap: &mut Heap) {
+    let live = live(heap);
+    println!("[heap-debug] {} live allocations, {} bytes", live.count, live.bytes);
+    let mut header = live.head;
+    while !header.is_null() {
+        let h = unsafe { &*header };
+        let object = header as usize + block_layout(Layout::from_size_align(h.size, h.align).unwrap()).1;
+        print_site(h, object);
+        header = h.next;
+    }
+}
+
+fn print_site(header: &AllocHeader, object: usize) {
+    crate::print!("[heap-debug]   #{} {:#x}, {} bytes, from", header.serial, object, header.size);
+    for &site in header.sites.iter().take_while(|&&site| site != 0) {
+        crate::print!(" {:#x}", site);
+    }
+    println!();
+}
+
+/// At shutdown: flush the console and list what was never freed, unless
+/// the heap is locked (we may be panicking inside the allocator).
+pub fn report_leaks(heap: Option<&mut Heap>) {
+    console::set_polled();
+    match heap {
+        Some(heap) => dump(heap),
+        None => {
+            println!("[heap-debug] heap is locked, no leak report");
+        }
+    }
+}
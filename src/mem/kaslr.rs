@@ -0,0 +1,286 @@
+//! Kernel address-space layout randomisation.
+//!
+//! The kernel is a position-independent executable, linked at
+//! `KERNEL_SPACE_BASE` above its load address. Before paging is on, the
+//! boot hart runs `kaslr_init` at the physical address: it picks where the
+//! image, the direct map and the kernel stacks go, applies the image's
+//! `R_RISCV_RELATIVE` relocations for the new image address and fills the
+//! boot page table to match. entry.asm then moves every hart into the image
+//! at `KERNEL_OFFSET` above its physical address.
+//!
+//! The kernel half of Sv39 is cut into 1 GiB slots. The last one keeps the
+//! trampoline and the kernel stacks, which start up to `KERNEL_STACK_SPREAD`
+//! below it. Of the others, `DIRECT_MAP_SIZE` worth of consecutive slots take
+//! the direct map and one more the image, at a 2 MiB boundary. The seed is
+//! the device tree's `/chosen/rng-seed`, if any, mixed with the `time` CSR.
+//! `nokaslr` in `/chosen/bootargs` keeps the linked layout, with the image
+//! in the direct map.
+//!
+//! Like the firmware, `kaslr_init` runs before the relocations are applied:
+//! it must not use `core::fmt`, trait objects or constants holding pointers,
+//! and it has no way to report a panic.
+
+use core::arch::asm;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::time;
+use crate::config::{kernel_stack_position, DIRECT_MAP_SIZE, KERNEL_SPACE_BASE, PAGE_SIZE, TRAMPOLINE};
+use crate::fdt::Fdt;
+use crate::mem::address::{PhysPageNum, VirtAddr};
+use crate::mem::memory_set::KERNEL_SPACE;
+use crate::mem::page_table::{PTEFlags, PageTableEntry};
+use crate::println;
+
+const SLOT_SIZE: usize = 1 << 30;
+/// Slots in the kernel half of Sv39; the last is not handed out.
+const SLOTS: usize = 256;
+/// The boot page table maps the image in pages of this size.
+const IMAGE_ALIGN: usize = 2 << 20;
+/// How far below the trampoline the kernel stacks may start.
+const KERNEL_STACK_SPREAD: usize = SLOT_SIZE / 2;
+/// `skernel` in linker.ld
+const LINK_ADDRESS: usize = KERNEL_SPACE_BASE + 0x8000_0000;
+const R_RISCV_RELATIVE: usize = 3;
+
+// Written by kaslr_init before rust_main clears .bss, hence .data. The
+// initial values are the nokaslr layout.
+
+/// Virtual minus physical address of the kernel image, read by entry.asm.
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".data.kaslr")]
+static KERNEL_OFFSET: AtomicUsize = AtomicUsize::new(KERNEL_SPACE_BASE);
+/// Virtual minus physical address in the direct map.
+#[unsafe(link_section = ".data.kaslr")]
+static PHYS_VIRT_OFFSET: AtomicUsize = AtomicUsize::new(KERNEL_SPACE_BASE);
+/// Top of the first kernel stack, read by trap.S.
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".data.kaslr")]
+static KERNEL_STACK_TOP: AtomicUsize = AtomicUsize::new(TRAMPOLINE);
+#[unsafe(link_section = ".data.kaslr")]
+static RANDOMISED: AtomicBool = AtomicBool::new(false);
+/// The seed included the device tree's `rng-seed`.
+#[unsafe(link_section = ".data.kaslr")]
+static RNG_SEED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    fn skernel();
+    fn ekernel();
+    fn srela_dyn();
+    fn erela_dyn();
+    fn boot_page_table();
+    fn boot_page_table_image();
+}
+
+/// An entry of `.rela.dyn`.
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
+}
+
+/// SplitMix64, enough to spread a seed over the layout.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+    fn feed(&mut self, value: u64) {
+        self.0 = self.next() ^ value;
+    }
+    /// A number in `0..bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound.max(1) as u64) as usize
+    }
+}
+
+struct Layout {
+    kernel_offset: usize,
+    phys_virt_offset: usize,
+    kernel_stack_top: usize,
+}
+
+/// Called by entry.asm on the boot hart, at the physical address, with
+/// paging off and the boot stack at its physical address.
+#[unsafe(no_mangle)]
+extern "C" fn kaslr_init(_hartid: usize, dtb_pa: usize) {
+    let (image_pa, image_size) = (skernel as usize, ekernel as usize - skernel as usize);
+    let (rng_seed, nokaslr) = chosen(dtb_pa);
+    let layout = if nokaslr {
+        Layout {
+            kernel_offset: KERNEL_SPACE_BASE,
+            phys_virt_offset: KERNEL_SPACE_BASE,
+            kernel_stack_top: TRAMPOLINE,
+        }
+    } else {
+        let mut rng = Rng(time::read() as u64);
+        for chunk in rng_seed.unwrap_or(&[]).chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            rng.feed(u64::from_le_bytes(bytes));
+        }
+        random_layout(&mut rng, image_pa, image_size)
+    };
+    relocate(image_pa, image_pa + layout.kernel_offset);
+    fill_boot_page_table(image_pa, image_size, &layout);
+    KERNEL_OFFSET.store(layout.kernel_offset, Ordering::Relaxed);
+    PHYS_VIRT_OFFSET.store(layout.phys_virt_offset, Ordering::Relaxed);
+    KERNEL_STACK_TOP.store(layout.kernel_stack_top, Ordering::Relaxed);
+    RANDOMISED.store(!nokaslr, Ordering::Relaxed);
+    RNG_SEED.store(!nokaslr && rng_seed.is_some(), Ordering::Relaxed);
+}
+
+/// `/chosen/rng-seed`, and whether `/chosen/bootargs` has `nokaslr`.
+fn chosen(dtb_pa: usize) -> (Option<&'static [u8]>, bool) {
+    if dtb_pa == 0 {
+        return (None, false);
+    }
+    let Ok(fdt) = (unsafe { Fdt::from_addr(dtb_pa) }) else {
+        return (None, false);
+    };
+    // Literals are compared through `*`: `name == "chosen"` compares
+    // `&&str`s, and the reference to the literal is a pointer constant.
+    let Some(chosen) = fdt.nodes().find(|node| node.depth == 1 && *node.name == *"chosen") else {
+        return (None, false);
+    };
+    // split by hand: str::split_whitespace goes through core's jump tables
+    let nokaslr = chosen
+        .property("bootargs")
+        .is_some_and(|args| args.split(|&b| b == b' ' || b == 0).any(|arg| *arg == *b"nokaslr"));
+    (chosen.property("rng-seed"), nokaslr)
+}
+
+fn random_layout(rng: &mut Rng, image_pa: usize, image_size: usize) -> Layout {
+    let direct_slots = DIRECT_MAP_SIZE / SLOT_SIZE;
+    let free_slots = SLOTS - 1 - direct_slots;
+    let direct_slot = rng.below(free_slots);
+    let mut image_slot = rng.below(free_slots);
+    if image_slot >= direct_slot {
+        image_slot += direct_slots;
+    }
+    let image_span = (image_pa % IMAGE_ALIGN + image_size).next_multiple_of(IMAGE_ALIGN);
+    let image_va = KERNEL_SPACE_BASE
+        + image_slot * SLOT_SIZE
+        + rng.below((SLOT_SIZE - image_span) / IMAGE_ALIGN + 1) * IMAGE_ALIGN
+        + image_pa % IMAGE_ALIGN;
+    Layout {
+        kernel_offset: image_va - image_pa,
+        phys_virt_offset: KERNEL_SPACE_BASE + direct_slot * SLOT_SIZE,
+        kernel_stack_top: TRAMPOLINE - rng.below(KERNEL_STACK_SPREAD / PAGE_SIZE) * PAGE_SIZE,
+    }
+}
+
+/// Apply the image's relocations for it to run at `image_va`.
+fn relocate(image_pa: usize, image_va: usize) {
+    let relas = unsafe {
+        core::slice::from_raw_parts(
+            srela_dyn as usize as *const Rela,
+            (erela_dyn as usize - srela_dyn as usize) / size_of::<Rela>(),
+        )
+    };
+    for rela in relas {
+        if rela.info != R_RISCV_RELATIVE {
+            // the PIE link leaves nothing else, and there is no console yet
+            loop {
+                unsafe { asm!("wfi") };
+            }
+        }
+        let target = (rela.offset - LINK_ADDRESS + image_pa) as *mut usize;
+        unsafe { target.write(rela.addend - LINK_ADDRESS + image_va) };
+    }
+}
+
+/// Map the direct map and the image as chosen, and the 1 GiB page the image
+/// is in at its physical address, where entry.asm turns paging on.
+fn fill_boot_page_table(image_pa: usize, image_size: usize, layout: &Layout) {
+    let root = boot_page_table as usize as *mut PageTableEntry;
+    let flags = PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::A | PTEFlags::D;
+    let root_index = |va: usize| SLOTS + (va - KERNEL_SPACE_BASE) / SLOT_SIZE;
+    let page = |pa: usize| PageTableEntry::new(PhysPageNum(pa / PAGE_SIZE), flags);
+    unsafe {
+        for i in 0..DIRECT_MAP_SIZE / SLOT_SIZE {
+            root.add(root_index(layout.phys_virt_offset) + i).write(page(i * SLOT_SIZE));
+        }
+        root.add(image_pa / SLOT_SIZE).write(page(image_pa / SLOT_SIZE * SLOT_SIZE));
+    }
+    if layout.kernel_offset == layout.phys_virt_offset {
+        return;
+    }
+    let table = boot_page_table_image as usize as *mut PageTableEntry;
+    let image_va = image_pa + layout.kernel_offset;
+    unsafe {
+        root.add(root_index(image_va))
+            .write(PageTableEntry::new(PhysPageNum(table as usize / PAGE_SIZE), PTEFlags::V));
+    }
+    let mut pa = image_pa / IMAGE_ALIGN * IMAGE_ALIGN;
+    while pa < image_pa + image_size {
+        let index = (pa + layout.kernel_offset) % SLOT_SIZE / IMAGE_ALIGN;
+        unsafe { table.add(index).write(page(pa)) };
+        pa += IMAGE_ALIGN;
+    }
+}
+
+/// Virtual minus physical address in the direct map.
+pub fn phys_virt_offset() -> usize {
+    PHYS_VIRT_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Virtual minus physical address of the kernel image.
+pub fn kernel_offset() -> usize {
+    KERNEL_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Top of the first kernel stack, see `kernel_stack_position`.
+pub fn kernel_stack_top() -> usize {
+    KERNEL_STACK_TOP.load(Ordering::Relaxed)
+}
+
+pub fn in_image(va: usize) -> bool {
+    (skernel as usize..ekernel as usize).contains(&va)
+}
+
+/// Where `va`, in the kernel image, is in the linked `os` file, which is
+/// what `addr2line -e os` needs.
+pub fn link_address(va: usize) -> usize {
+    va - skernel as usize + LINK_ADDRESS
+}
+
+pub fn print_info() {
+    if !RANDOMISED.load(Ordering::Relaxed) {
+        println!("[kernel] KASLR off (nokaslr)");
+    } else if RNG_SEED.load(Ordering::Relaxed) {
+        println!("[kernel] KASLR on, seeded from rng-seed and time");
+    } else {
+        println!("[kernel] KASLR on, seeded from time only");
+    }
+}
+
+#[allow(unused)]
+pub fn kaslr_test() {
+    // pointers stored in the image were relocated
+    static SELF: fn() = kaslr_test;
+    assert_eq!(unsafe { read_volatile(&SELF) } as usize, kaslr_test as usize);
+    // the image is mapped at KERNEL_OFFSET
+    let va = kaslr_test as usize;
+    let pte = KERNEL_SPACE.exclusive_access().translate(VirtAddr::from(va).floor()).unwrap();
+    assert_eq!(pte.ppn().0, (va - kernel_offset()) / PAGE_SIZE);
+    assert_eq!(link_address(skernel as usize), LINK_ADDRESS);
+    // the regions keep to their slots
+    let image = skernel as usize..ekernel as usize;
+    let direct = phys_virt_offset()..phys_virt_offset() + DIRECT_MAP_SIZE;
+    let last_slot = KERNEL_SPACE_BASE + (SLOTS - 1) * SLOT_SIZE;
+    assert!(image.start >= KERNEL_SPACE_BASE && image.end <= last_slot);
+    assert!(direct.start >= KERNEL_SPACE_BASE && direct.end <= last_slot);
+    if RANDOMISED.load(Ordering::Relaxed) {
+        assert!(image.end <= direct.start || image.start >= direct.end);
+        assert_eq!(image.start / SLOT_SIZE, (image.end - 1) / SLOT_SIZE);
+    }
+    let (bottom, top) = kernel_stack_position(0);
+    assert!(top <= TRAMPOLINE && bottom >= TRAMPOLINE - KERNEL_STACK_SPREAD - PAGE_SIZE * 2);
+    println!("kaslr_test passed!");
+}
@@ -0,0 +1,178 @@
+//! Kernel stacks of tasks.
+//!
+//! Stack `id` is mapped in `KERNEL_SPACE` at `kernel_stack_position(id)`,
+//! below the unmapped page that separates it from stack `id + 1`. Running
+//! off the bottom of a stack faults on that guard page instead of writing
+//! over the next stack; trap.S moves the trap frame to the hart's overflow
+//! stack in that case, and the trap handler names the task with
+//! `guard_page_owner`.
+
+use core::arch::asm;
+use alloc::vec::Vec;
+use crate::config::{kernel_stack_position, KERNEL_STACK_SIZE, MAX_KERNEL_STACKS, PAGE_SIZE};
+use crate::mem::address::VirtAddr;
+use crate::mem::frame_allocator::FrameOwner;
+use crate::mem::memory_set::{MapPermission, KERNEL_SPACE};
+use crate::sync::SpinLockIrq;
+use crate::{cpu, println, sbi};
+
+/// Hands out the smallest ids it can, reusing freed ones first.
+struct RecycleAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+impl RecycleAllocator {
+    const fn new() -> Self {
+        Self {
+            current: 0,
+            recycled: Vec::new(),
+        }
+    }
+    fn alloc(&mut self) -> Option<usize> {
+        if let Some(id) = self.recycled.pop() {
+            Some(id)
+        } else if self.current < MAX_KERNEL_STACKS {
+            self.current += 1;
+            Some(self.current - 1)
+        } else {
+            None
+        }
+    }
+    fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current, "kernel stack {} was never allocated", id);
+        assert!(!self.recycled.contains(&id), "kernel stack {} freed twice", id);
+        self.recycled.push(id);
+    }
+}
+
+static KSTACK_ALLOCATOR: SpinLockIrq<RecycleAllocator> =
+    SpinLockIrq::new(RecycleAllocator::new()).named("KSTACK_ALLOCATOR");
+
+/// A `KERNEL_STACK_SIZE` stack mapped in `KERNEL_SPACE`, unmapped on drop.
+pub struct KernelStack {
+    id: usize,
+}
+
+impl KernelStack {
+    pub fn new() -> Self {
+        let id = KSTACK_ALLOCATOR
+            .exclusive_access()
+            .alloc()
+            .expect("out of kernel stacks");
+        let (bottom, top) = kernel_stack_position(id);
+        KERNEL_SPACE.exclusive_access().insert_framed_area(
+            bottom.into(),
+            top.into(),
+            MapPermission::R | MapPermission::W,
+            FrameOwner::KernelStack,
+        );
+        Self { id }
+    }
+    pub fn id(&self) -> usize {
+        self.id
+    }
+    pub fn bottom(&self) -> usize {
+        kernel_stack_position(self.id).0
+    }
+    pub fn top(&self) -> usize {
+        kernel_stack_position(self.id).1
+    }
+}
+
+impl Default for KernelStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (bottom, _) = kernel_stack_position(self.id);
+        let freed = KERNEL_SPACE
+            .exclusive_access()
+            .remove_area_with_start_vpn(VirtAddr::from(bottom).floor());
+        // Any hart may still cache the stack's leaf entries and the tables
+        // above them. `sfence.vma` with an address leaves non-leaf entries
+        // alone, so flush everything, everywhere, before the frames go
+        // back to the allocator and the id gets reused.
+        unsafe {
+            asm!("sfence.vma");
+        }
+        let others = cpu::online_harts() & !(1 << cpu::hartid());
+        if others != 0 {
+            sbi::remote_sfence_vma(others, 0, 0, usize::MAX);
+        }
+        drop(freed);
+        KSTACK_ALLOCATOR.exclusive_access().dealloc(self.id);
+    }
+}
+
+/// The id of the kernel stack whose guard page holds `addr`, if any.
+pub fn guard_page_owner(addr: usize) -> Option<usize> {
+    let offset = kernel_stack_position(0).1.wrapping_sub(addr).wrapping_sub(1);
+    let stride = KERNEL_STACK_SIZE + PAGE_SIZE;
+    if offset < MAX_KERNEL_STACKS * stride && offset % stride >= KERNEL_STACK_SIZE {
+        Some(offset / stride)
+    } else {
+        None
+    }
+}
+
+/// Call `f` with `sp` at `sp`, then switch back.
+unsafe fn call_on_stack(sp: usize, f: extern "C" fn()) {
+    unsafe {
+        asm!(
+            "mv s2, sp",
+            "mv sp, {sp}",
+            "jalr {f}",
+            "mv sp, s2",
+            sp = in(reg) sp,
+            f = in(reg) f,
+            out("s2") _,
+            clobber_abi("C"),
+        );
+    }
+}
+
+#[allow(unused)]
+pub fn kernel_stack_test() {
+    let mapped = |va: usize| {
+        KERNEL_SPACE
+            .exclusive_access()
+            .translate(VirtAddr::from(va).floor())
+            .is_some_and(|pte| pte.is_valid())
+    };
+    let a = KernelStack::new();
+    let b = KernelStack::new();
+    assert_ne!(a.id(), b.id());
+    assert_eq!(a.top() - a.bottom(), KERNEL_STACK_SIZE);
+    assert!(mapped(b.bottom()) && mapped(b.top() - 1));
+    // the guard page below each stack stays unmapped
+    assert!(!mapped(b.bottom() - 1) && !mapped(b.bottom() - PAGE_SIZE));
+    assert_eq!(guard_page_owner(b.bottom() - 1), Some(b.id()));
+    assert_eq!(guard_page_owner(b.bottom() - PAGE_SIZE), Some(b.id()));
+    assert_eq!(guard_page_owner(b.bottom()), None);
+    assert_eq!(guard_page_owner(b.top() - 1), None);
+    assert_eq!(guard_page_owner(a.top()), None);
+    // ids are recycled
+    let (id, bottom) = (a.id(), a.bottom());
+    drop(a);
+    assert!(!mapped(bottom));
+    let c = KernelStack::new();
+    assert_eq!(c.id(), id);
+    assert!(mapped(c.bottom()));
+    // traps taken on a kernel stack, and with no room left on it, which
+    // trap.S sends to the overflow stack
+    extern "C" fn breakpoint() {
+        unsafe {
+            asm!("ebreak");
+        }
+    }
+    unsafe {
+        call_on_stack(c.top(), breakpoint);
+        asm!("mv s2, sp", "mv sp, {}", "ebreak", "mv sp, s2", in(reg) c.bottom(), out("s2") _);
+    }
+    drop((b, c));
+    println!("kernel_stack_test passed!");
+}
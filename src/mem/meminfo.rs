@@ -0,0 +1,92 @@
+//! Memory usage: frames by owner and the state of the kernel heap.
+
+use core::fmt;
+use crate::config::PAGE_SIZE;
+use crate::println;
+use super::frame_allocator::{frame_alloc, frame_counts, frames_owned, FrameOwner};
+use super::heap_allocator::{heap_stats, HeapStats};
+
+/// A snapshot of memory usage. Plain `repr(C)` data, so it can be copied
+/// out to user space as is.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MemInfo {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// indexed by `FrameOwner as usize`
+    pub owned_frames: [usize; FrameOwner::COUNT],
+    pub heap: HeapStats,
+}
+
+impl MemInfo {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn owned(&self, owner: FrameOwner) -> usize {
+        self.owned_frames[owner as usize]
+    }
+}
+
+pub fn meminfo() -> MemInfo {
+    let (free_frames, total_frames) = frame_counts();
+    MemInfo {
+        total_frames,
+        free_frames,
+        owned_frames: FrameOwner::ALL.map(frames_owned),
+        heap: heap_stats(),
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |frames: usize| frames * PAGE_SIZE / 1024;
+        writeln!(
+            f,
+            "[meminfo] frames: {} total, {} used, {} free ({} KiB free)",
+            self.total_frames,
+            self.used_frames(),
+            self.free_frames,
+            kib(self.free_frames)
+        )?;
+        for owner in FrameOwner::ALL {
+            writeln!(f, "[meminfo]   {:<12} {:>8} frames", owner.name(), self.owned(owner))?;
+        }
+        let heap = &self.heap;
+        writeln!(
+            f,
+            "[meminfo] heap: {} / {} KiB used ({} KiB requested), peak {} KiB",
+            heap.allocated / 1024,
+            heap.total / 1024,
+            heap.user / 1024,
+            heap.peak / 1024
+        )?;
+        write!(
+            f,
+            "[meminfo]   largest free block {} KiB, fragmentation {}%",
+            heap.largest_free / 1024,
+            heap.fragmentation()
+        )
+    }
+}
+
+#[allow(unused)]
+pub fn meminfo_test() {
+    use alloc::vec::Vec;
+    let before = meminfo();
+    let frame = frame_alloc(FrameOwner::KernelStack).unwrap();
+    let buffer: Vec<u8> = Vec::with_capacity(64 * 1024);
+    let during = meminfo();
+    assert_eq!(during.free_frames, before.free_frames - 1);
+    assert_eq!(during.owned(FrameOwner::KernelStack), before.owned(FrameOwner::KernelStack) + 1);
+    assert!(during.heap.allocated >= before.heap.allocated + 64 * 1024);
+    assert!(during.heap.peak >= during.heap.allocated);
+    assert!(during.heap.largest_free <= during.heap.free());
+    drop(buffer);
+    drop(frame);
+    let after = meminfo();
+    assert_eq!(after.free_frames, before.free_frames);
+    assert_eq!(after.heap.allocated, before.heap.allocated);
+    println!("{}", after);
+    println!("meminfo_test passed!");
+}
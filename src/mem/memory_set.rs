@@ -1,7 +1,25 @@
+use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::asm;
 use lazy_static::lazy_static;
+use riscv::register::satp::{self, Satp};
+use crate::config::{
+    DIRECT_MAP_SIZE, ELF_ET_DYN_BASE, ELF_INTERP_BASE, MAX_HUGE_PAGE_LEVEL, PAGE_SIZE, PAGE_SIZE_BITS, TRAMPOLINE,
+    USER_STACK_SIZE,
+};
+use crate::drivers::uart::UART_BASE;
+use crate::mem::address::{phys_to_virt, virt_to_phys, PhysAddr, PhysPageNum, StepByOne, VPNRange, VirtAddr, VirtPageNum};
+use crate::mem::elf::{self, ElfError, ElfInfo, Segment};
+use crate::mem::kaslr::{kernel_offset, phys_virt_offset};
+use crate::mem::frame_allocator::{frame_alloc, FrameOwner, FrameTracker};
+use crate::mem::page_table::{pages_at, PTEFlags, PageTable, PageTableEntry};
+use crate::platform::{MmioDevice, PLATFORM};
+use crate::println;
+use crate::sync::SpinLockIrq;
 
-extern "C" {
+unsafe extern "C" {
+    fn skernel();
     fn stext();
     fn etext();
     fn srodata();
@@ -15,8 +33,8 @@ extern "C" {
 }
 
 lazy_static! {
-    pub static ref KERNEL_SPACE: Arc<UPIntrFreeCell<MemorySet>> =
-        Arc::new(unsafe { UPIntrFreeCell::new(MemorySet::new_kernel()) });
+    pub static ref KERNEL_SPACE: Arc<SpinLockIrq<MemorySet>> =
+        Arc::new(SpinLockIrq::new(MemorySet::new_kernel()).named("KERNEL_SPACE"));
 }
 
 pub fn kernel_token() -> usize {
@@ -44,21 +62,28 @@ impl MemorySet {
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
+        owner: FrameOwner,
     ) {
         self.push(
-            MapArea::new(start_va, end_va, MapType::Framed, permission),
+            MapArea::new_framed(start_va, end_va, permission, owner),
             None,
         );
     }
-    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
+    /// Returns the frames the area used, data and page tables alike, for
+    /// the caller to drop once no TLB can still reach them.
+    #[must_use]
+    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) -> Vec<FrameTracker> {
         if let Some((idx, area)) = self
             .areas
             .iter_mut()
             .enumerate()
             .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
         {
-            area.unmap(&mut self.page_table);
+            let freed = area.unmap(&mut self.page_table);
             self.areas.remove(idx);
+            freed
+        } else {
+            Vec::new()
         }
     }
     /// Add a new MapArea into this MemorySet.
@@ -75,7 +100,7 @@ impl MemorySet {
     fn map_trampoline(&mut self) {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
-            PhysAddr::from(strampoline as usize).into(),
+            PhysAddr::from(virt_to_phys(strampoline as usize)).into(),
             PTEFlags::R | PTEFlags::X,
         );
     }
@@ -97,7 +122,7 @@ impl MemorySet {
             MapArea::new(
                 (stext as usize).into(),
                 (etext as usize).into(),
-                MapType::Identical,
+                MapType::kernel_image(),
                 MapPermission::R | MapPermission::X,
             ),
             None,
@@ -107,7 +132,7 @@ impl MemorySet {
             MapArea::new(
                 (srodata as usize).into(),
                 (erodata as usize).into(),
-                MapType::Identical,
+                MapType::kernel_image(),
                 MapPermission::R,
             ),
             None,
@@ -117,7 +142,7 @@ impl MemorySet {
             MapArea::new(
                 (sdata as usize).into(),
                 (edata as usize).into(),
-                MapType::Identical,
+                MapType::kernel_image(),
                 MapPermission::R | MapPermission::W,
             ),
             None,
@@ -127,81 +152,145 @@ impl MemorySet {
             MapArea::new(
                 (sbss_with_stack as usize).into(),
                 (ebss as usize).into(),
-                MapType::Identical,
+                MapType::kernel_image(),
                 MapPermission::R | MapPermission::W,
             ),
             None,
         );
         // println!("mapping physical memory");
-        memory_set.push(
-            MapArea::new(
-                (ekernel as usize).into(),
-                MEMORY_END.into(),
-                MapType::Identical,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
+        let (memory, devices) = {
+            let platform = PLATFORM.read();
+            (platform.memory.clone(), platform.mmio_devices())
+        };
+        // the kernel image is in there too, mapped above by section
+        let image = virt_to_phys(skernel as usize)..virt_to_phys(ekernel as usize);
+        for region in memory.iter() {
+            let region_end = region.end().min(DIRECT_MAP_SIZE);
+            for (start, end) in [
+                (region.start, region_end.min(image.start)),
+                (region.start.max(image.end), region_end),
+            ] {
+                if start < end {
+                    memory_set.push(
+                        MapArea::new(
+                            phys_to_virt(start).into(),
+                            phys_to_virt(end).into(),
+                            MapType::direct_map(),
+                            MapPermission::R | MapPermission::W,
+                        ),
+                        None,
+                    );
+                }
+            }
+        }
         //println!("mapping memory-mapped registers");
-        for pair in MMIO {
+        for (start, end) in mmio_ranges(&devices) {
             memory_set.push(
                 MapArea::new(
-                    (*pair).0.into(),
-                    ((*pair).0 + (*pair).1).into(),
-                    MapType::Identical,
-                    MapPermission::R | MapPermission::W,
+                    phys_to_virt(start).into(),
+                    phys_to_virt(end).into(),
+                    MapType::direct_map(),
+                    MapPermission::R | MapPermission::W | MapPermission::IO,
                 ),
                 None,
             );
         }
         memory_set
     }
-    /// Include sections in elf and trampoline,
-    /// also returns user_sp_base and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    /// The address space of the program in `elf_data`: its segments, the
+    /// trampoline and a user stack a guard page above the segments,
+    /// executable if `PT_GNU_STACK` says so. An interpreter named by
+    /// `PT_INTERP` is left to `load_interpreter`.
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, ElfInfo), ElfError> {
         let mut memory_set = Self::new_bare();
+        let elf = elf::parse(elf_data, ELF_ET_DYN_BASE, memory_set.user_space_end())?;
         // map trampoline
         memory_set.map_trampoline();
-        // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
-        let elf_header = elf.header;
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
-        let ph_count = elf_header.pt2.ph_count();
-        let mut max_end_vpn = VirtPageNum(0);
-        for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
-                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
-                let mut map_perm = MapPermission::U;
-                let ph_flags = ph.flags();
-                if ph_flags.is_read() {
-                    map_perm |= MapPermission::R;
-                }
-                if ph_flags.is_write() {
-                    map_perm |= MapPermission::W;
-                }
-                if ph_flags.is_execute() {
-                    map_perm |= MapPermission::X;
+        memory_set.map_segments(&elf.segments)?;
+        let max_end: usize = VirtAddr::from(elf.segments.iter().map(|s| s.end).max().unwrap()).ceil().into();
+        let user_stack_bottom = max_end + PAGE_SIZE;
+        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        if user_stack_top > memory_set.user_space_end() {
+            return Err(ElfError::BadSegment);
+        }
+        let mut stack_perm = MapPermission::R | MapPermission::W | MapPermission::U;
+        if elf.exec_stack {
+            stack_perm |= MapPermission::X;
+        }
+        memory_set.insert_framed_area(user_stack_bottom.into(), user_stack_top.into(), stack_perm, FrameOwner::UserData);
+        let info = ElfInfo {
+            start: elf.entry,
+            entry: elf.entry,
+            phdr: elf.phdr,
+            phnum: elf.phnum,
+            interp_base: 0,
+            interp: elf.interp,
+            user_sp: user_stack_top,
+        };
+        Ok((memory_set, info))
+    }
+    /// Map the interpreter `info.interp` names, read into `interp_data`,
+    /// and start there instead.
+    pub fn load_interpreter(&mut self, info: &mut ElfInfo, interp_data: &[u8]) -> Result<(), ElfError> {
+        let interp = elf::parse(interp_data, ELF_INTERP_BASE, self.user_space_end())?;
+        if interp.interp.is_some() {
+            return Err(ElfError::BadInterp);
+        }
+        self.map_segments(&interp.segments)?;
+        info.interp_base = interp.bias;
+        info.start = interp.entry;
+        Ok(())
+    }
+    /// Map `segments` in Framed areas and copy in their data. A page that
+    /// several segments share gets the permissions of all of them.
+    fn map_segments(&mut self, segments: &[Segment]) -> Result<(), ElfError> {
+        let mut pages: BTreeMap<VirtPageNum, MapPermission> = BTreeMap::new();
+        for segment in segments {
+            let start: VirtAddr = segment.start.into();
+            let end: VirtAddr = segment.end.into();
+            for vpn in VPNRange::new(start.floor(), end.ceil()) {
+                *pages.entry(vpn).or_insert(MapPermission::empty()) |= segment.perm;
+            }
+        }
+        if pages.keys().any(|&vpn| self.translate(vpn).is_some_and(|pte| pte.is_valid())) {
+            return Err(ElfError::Overlap);
+        }
+        // one area for each run of pages with the same permissions
+        let mut run: Option<(VirtPageNum, VirtPageNum, MapPermission)> = None;
+        for (&vpn, &perm) in pages.iter() {
+            match run {
+                Some((_, ref mut end, run_perm)) if *end == vpn && run_perm == perm => end.step(),
+                _ => {
+                    if let Some((start, end, perm)) = run {
+                        self.push(MapArea::new_framed(start.into(), end.into(), perm, FrameOwner::UserData), None);
+                    }
+                    let mut end = vpn;
+                    end.step();
+                    run = Some((vpn, end, perm));
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
             }
         }
-        let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_base: usize = max_end_va.into();
-        user_stack_base += PAGE_SIZE;
-        (
-            memory_set,
-            user_stack_base,
-            elf.header.pt2.entry_point() as usize,
-        )
+        if let Some((start, end, perm)) = run {
+            self.push(MapArea::new_framed(start.into(), end.into(), perm, FrameOwner::UserData), None);
+        }
+        for segment in segments {
+            self.write_bytes(segment.start, segment.data);
+        }
+        Ok(())
+    }
+    /// Copy `data` to `va` through this address space, which must map it.
+    fn write_bytes(&self, mut va: usize, mut data: &[u8]) {
+        while !data.is_empty() {
+            let addr = VirtAddr::from(va);
+            let offset = addr.page_offset();
+            let len = data.len().min(PAGE_SIZE - offset);
+            let ppn = self.translate(addr.floor()).unwrap().ppn();
+            ppn.get_bytes_array()[offset..offset + len].copy_from_slice(&data[..len]);
+            va += len;
+            data = &data[len..];
+        }
     }
+    #[allow(unused)]
     pub fn from_existed_user(user_space: &MemorySet) -> MemorySet {
         let mut memory_set = Self::new_bare();
         // map trampoline
@@ -224,16 +313,27 @@ impl MemorySet {
     pub fn activate(&self) {
         let satp = self.page_table.token();
         unsafe {
-            satp::write(satp);
+            satp::write(Satp::from_bits(satp));
             asm!("sfence.vma");
         }
     }
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
-    pub fn recycle_data_pages(&mut self) {
-        //*self = Self::new_bare();
+    /// End of user space: the lower half of what the paging mode translates.
+    pub fn user_space_end(&self) -> usize {
+        1 << (self.page_table.mode().va_bits() - 1)
+    }
+    /// Unmap every area, returning their frames as `remove_area_with_start_vpn` does.
+    #[allow(unused)]
+    #[must_use]
+    pub fn recycle_data_pages(&mut self) -> Vec<FrameTracker> {
+        let mut freed = Vec::new();
+        for area in self.areas.iter_mut() {
+            freed.extend(area.unmap(&mut self.page_table));
+        }
         self.areas.clear();
+        freed
     }
 }
 
@@ -242,6 +342,8 @@ pub struct MapArea {
     data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     map_type: MapType,
     map_perm: MapPermission,
+    /// What the frames of a Framed area are counted as.
+    owner: FrameOwner,
 }
 
 impl MapArea {
@@ -258,6 +360,13 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            owner: FrameOwner::Other,
+        }
+    }
+    pub fn new_framed(start_va: VirtAddr, end_va: VirtAddr, map_perm: MapPermission, owner: FrameOwner) -> Self {
+        Self {
+            owner,
+            ..Self::new(start_va, end_va, MapType::Framed, map_perm)
         }
     }
     pub fn from_another(another: &MapArea) -> Self {
@@ -266,44 +375,97 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            owner: another.owner,
+        }
+    }
+    /// Where `vpn` goes in an Identical or Linear area.
+    fn direct_ppn(&self, page_table: &PageTable, vpn: VirtPageNum) -> PhysPageNum {
+        match self.map_type {
+            MapType::Identical => PhysPageNum(vpn.0),
+            MapType::Linear(pn_offset) => {
+                // must fit the paging mode, and so wraps around like virtual addresses
+                let vpn_end = 1isize << (page_table.mode().va_bits() - PAGE_SIZE_BITS);
+                assert!((vpn.0 as isize) < vpn_end);
+                PhysPageNum((vpn.0 as isize + pn_offset).rem_euclid(vpn_end) as usize)
+            }
+            MapType::Framed => unreachable!(),
+        }
+    }
+    /// Split an Identical or Linear area into the largest pages that fit,
+    /// as (first vpn, level).
+    fn direct_pages(&self, page_table: &PageTable) -> Vec<(VirtPageNum, usize)> {
+        let max_level = MAX_HUGE_PAGE_LEVEL.min(page_table.mode().levels() - 1);
+        let (mut vpn, end) = (self.vpn_range.get_start(), self.vpn_range.get_end());
+        let mut pages = Vec::new();
+        while vpn < end {
+            let ppn = self.direct_ppn(page_table, vpn);
+            let level = (0..=max_level)
+                .rev()
+                .find(|&level| {
+                    let size = pages_at(level);
+                    vpn.0.is_multiple_of(size) && ppn.0.is_multiple_of(size) && vpn.0 + size <= end.0
+                })
+                .unwrap();
+            pages.push((vpn, level));
+            vpn = VirtPageNum(vpn.0 + pages_at(level));
+        }
+        pages
+    }
+    /// Kernel mappings get A and D set upfront: a hart that doesn't update
+    /// them in hardware would take a page fault the kernel can't handle.
+    fn pte_flags(&self) -> PTEFlags {
+        let flags = PTEFlags::from_bits(self.map_perm.bits()).unwrap();
+        if self.map_perm.contains(MapPermission::U) {
+            flags
+        } else {
+            flags | PTEFlags::A | PTEFlags::D
         }
     }
     pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
         let ppn: PhysPageNum;
         match self.map_type {
-            MapType::Identical => {
-                ppn = PhysPageNum(vpn.0);
+            MapType::Identical | MapType::Linear(_) => {
+                ppn = self.direct_ppn(page_table, vpn);
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc(self.owner).unwrap();
                 ppn = frame.ppn;
                 self.data_frames.insert(vpn, frame);
             }
-            MapType::Linear(pn_offset) => {
-                // check for sv39
-                assert!(vpn.0 < (1usize << 27));
-                ppn = PhysPageNum((vpn.0 as isize + pn_offset) as usize);
-            }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        let pte_flags = self.pte_flags();
         page_table.map(vpn, ppn, pte_flags);
     }
-    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+    /// Returns the page's data frame, if any, and the tables left empty.
+    #[allow(unused)]
+    #[must_use]
+    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Vec<FrameTracker> {
+        let mut freed = page_table.unmap(vpn);
         if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+            freed.extend(self.data_frames.remove(&vpn));
         }
-        page_table.unmap(vpn);
+        freed
     }
     pub fn map(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+        if self.map_type == MapType::Framed {
+            for vpn in self.vpn_range {
+                self.map_one(page_table, vpn);
+            }
+            return;
         }
-    }
-    pub fn unmap(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.unmap_one(page_table, vpn);
+        let pte_flags = self.pte_flags();
+        for (vpn, level) in self.direct_pages(page_table) {
+            let ppn = self.direct_ppn(page_table, vpn);
+            page_table.map_huge(vpn, ppn, level, pte_flags);
         }
     }
+    /// Returns the data frames and the page tables left empty, still allocated.
+    #[must_use]
+    pub fn unmap(&mut self, page_table: &mut PageTable) -> Vec<FrameTracker> {
+        let mut freed: Vec<FrameTracker> = core::mem::take(&mut self.data_frames).into_values().collect();
+        freed.extend(page_table.unmap_range(self.vpn_range.get_start(), self.vpn_range.get_end()));
+        freed
+    }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
     pub fn copy_data(&mut self, page_table: &PageTable, data: &[u8]) {
@@ -328,20 +490,56 @@ impl MapArea {
     }
 }
 
+/// Page-aligned [start, end) ranges covering `devices`, which are sorted
+/// by base; devices sharing a page end up in one range.
+fn mmio_ranges(devices: &[MmioDevice]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for device in devices {
+        let start = device.base & !(PAGE_SIZE - 1);
+        let end = (device.base + device.size).next_multiple_of(PAGE_SIZE);
+        match ranges.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+            _ => ranges.push((start, end)),
+        }
+    }
+    ranges
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum MapType {
+    #[allow(unused)]
     Identical,
     Framed,
     /// offset of page num
     Linear(isize),
 }
 
+impl MapType {
+    /// Physical address `pa` at `offset + pa`. The page number offset depends
+    /// on the paging mode, as page numbers only keep the bits it translates.
+    pub fn linear(offset: usize) -> Self {
+        Self::Linear(-(VirtAddr::from(offset).floor().0 as isize))
+    }
+    pub fn direct_map() -> Self {
+        Self::linear(phys_virt_offset())
+    }
+    pub fn kernel_image() -> Self {
+        Self::linear(kernel_offset())
+    }
+}
+
 bitflags! {
-    pub struct MapPermission: u8 {
+    /// The same bits as in `PTEFlags`.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub struct MapPermission: usize {
         const R = 1 << 1;
         const W = 1 << 2;
         const X = 1 << 3;
         const U = 1 << 4;
+        /// Uncached main memory, e.g. frame buffers
+        const UNCACHED = 1 << 61;
+        /// Device registers: uncached and strongly ordered
+        const IO = 1 << 62;
     }
 }
 
@@ -351,6 +549,7 @@ pub fn remap_test() {
     let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
     let mid_rodata: VirtAddr = ((srodata as usize + erodata as usize) / 2).into();
     let mid_data: VirtAddr = ((sdata as usize + edata as usize) / 2).into();
+    let mid_bss: VirtAddr = ((sbss_with_stack as usize + ebss as usize) / 2).into();
     assert!(!kernel_space
         .page_table
         .translate(mid_text.floor())
@@ -366,5 +565,21 @@ pub fn remap_test() {
         .translate(mid_data.floor())
         .unwrap()
         .executable(),);
+    assert!(!kernel_space
+        .page_table
+        .translate(mid_bss.floor())
+        .unwrap()
+        .executable(),);
+    // the console must stay reachable once paging is on
+    let uart = PLATFORM.read().uart.map_or(UART_BASE, |uart| uart.base);
+    assert_eq!(
+        kernel_space.page_table.translate_va(phys_to_virt(uart).into()).map(|pa| pa.0),
+        Some(uart)
+    );
+    // and the lower half is left to user space
+    assert!(kernel_space
+        .page_table
+        .translate_va(virt_to_phys(mid_text.into()).into())
+        .is_none());
     println!("remap_test passed!");
 }
\ No newline at end of file
@@ -4,3 +4,39 @@ mod frame_allocator;
 mod address;
 mod page_table;
 mod memory_set;
+mod elf;
+mod meminfo;
+#[cfg(feature = "heap-debug")]
+mod heap_debug;
+mod slab;
+pub mod kaslr;
+mod kernel_stack;
+
+pub use address::{phys_to_virt, virt_to_phys};
+#[allow(unused)]
+pub use kaslr::{kernel_offset, kernel_stack_top, phys_virt_offset};
+#[allow(unused)]
+pub use kernel_stack::{guard_page_owner, kernel_stack_test, KernelStack};
+#[allow(unused)]
+pub use elf::{elf_loader_test, ElfError, ElfInfo};
+#[allow(unused)]
+pub use heap_allocator::{heap_stats, HeapStats};
+#[allow(unused)]
+pub use memory_set::{kernel_token, remap_test, MapArea, MapPermission, MapType, MemorySet, KERNEL_SPACE};
+#[allow(unused)]
+pub use meminfo::{meminfo, meminfo_test, MemInfo};
+#[allow(unused)]
+pub use page_table::{
+    init_paging_mode, page_table_teardown_test, page_table_test, pages_at, paging_mode, PTEFlags, PageTable,
+    PageTableEntry, PagingMode, NAPOT_PAGES,
+};
+#[allow(unused)]
+pub use page_table::{translated_byte_buffer, translated_ref, translated_refmut, translated_str, UserBuffer};
+#[allow(unused)]
+pub use slab::{slab_test, CacheStats, KmemCache};
+#[allow(unused)]
+pub use frame_allocator::{
+    frame_alloc, frame_alloc_contiguous, frame_alloc_contiguous_test, frame_allocator_test, frame_counts,
+    frames_owned, init_frame_allocator, BitmapFrameAllocator, BuddyFrameAllocator, ContiguousFrames,
+    FrameAllocator, FrameOwner, FrameTracker, LinkedListFrameAllocator, StackFrameAllocator,
+};
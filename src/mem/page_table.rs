@@ -1,14 +1,21 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
-use alloc::vec;
 use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::satp;
 use crate::mem::address::{PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
-use crate::mem::frame_allocator::{frame_alloc, FrameTracker};
+use crate::mem::frame_allocator::{frame_alloc, FrameOwner, FrameTracker};
+use crate::config::MAX_PAGING_LEVELS;
+use crate::platform::PLATFORM;
+use crate::println;
 
 use bitflags::*;
 
 bitflags! {
+    /// Every bit of a PTE except the PPN.
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-    pub struct PTEFlags: u8 {
+    pub struct PTEFlags: usize {
         const V = 1 << 0;
         const R = 1 << 1;
         const W = 1 << 2;
@@ -17,9 +24,131 @@ bitflags! {
         const G = 1 << 5;
         const A = 1 << 6;
         const D = 1 << 7;
+        /// Reserved for software, ignored by the hardware
+        const RSW0 = 1 << 8;
+        const RSW1 = 1 << 9;
+        /// Svpbmt: non-cacheable, idempotent, weakly-ordered main memory
+        const NC = 1 << 61;
+        /// Svpbmt: non-cacheable, non-idempotent, strongly-ordered I/O
+        const IO = 1 << 62;
+        /// Svnapot: one of the 16 PTEs of a 64 KiB page
+        const N = 1 << 63;
     }
 }
 
+impl PTEFlags {
+    /// A writable page shared read-only until the first write
+    pub const COW: Self = Self::RSW0;
+    /// The Svpbmt memory type; neither bit means the PMA of the region
+    pub const PBMT: Self = Self::NC.union(Self::IO);
+}
+
+const PPN_MASK: usize = ((1 << 44) - 1) << 10;
+/// Pages in a Svnapot page
+pub const NAPOT_PAGES: usize = 16;
+
+/// Svpbmt is on: otherwise memory types are dropped from the PTEs and the
+/// PMAs apply, which already make MMIO regions I/O.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+static SVNAPOT: AtomicBool = AtomicBool::new(false);
+
+/// What the hart makes of `flags`: bits of extensions it lacks are reserved.
+fn supported(flags: PTEFlags) -> PTEFlags {
+    if SVPBMT.load(Ordering::Relaxed) { flags } else { flags - PTEFlags::PBMT }
+}
+
+/// Translation scheme, as the MODE field of `satp`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    pub const ALL: [PagingMode; 3] = [Self::Sv39, Self::Sv48, Self::Sv57];
+
+    pub fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+
+    pub fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    pub fn from_satp(satp: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|&mode| mode as usize == satp >> 60)
+    }
+
+    /// Try the mode out on this hart: `satp` ignores writes of unsupported
+    /// modes. Translation is live in between, so `root` must map the code
+    /// running here.
+    fn supported(self, root: PhysPageNum) -> bool {
+        let satp = (self as usize) << 60 | root.0;
+        let read: usize;
+        unsafe {
+            asm!(
+                "csrrw {old}, satp, {new}",
+                "sfence.vma",
+                "csrr {read}, satp",
+                "csrw satp, {old}",
+                "sfence.vma",
+                old = out(reg) _,
+                new = in(reg) satp,
+                read = out(reg) read,
+            );
+        }
+        read == satp
+    }
+}
+
+/// Mode of the page tables created from now on.
+static PAGING_MODE: AtomicUsize = AtomicUsize::new(PagingMode::Sv39 as usize);
+
+pub fn paging_mode() -> PagingMode {
+    PagingMode::from_satp(PAGING_MODE.load(Ordering::Relaxed) << 60).unwrap()
+}
+
+/// Probe which paging modes this hart implements and use the largest one
+/// allowed by `MAX_PAGING_LEVELS`. Must run on the boot page table, before
+/// any other page table is built.
+pub fn init_paging_mode() {
+    // The boot page table is an Sv39 root. In Sv48 and Sv57 its kernel half
+    // is under the last entry of each level above that, so chaining the
+    // boot table under such entries keeps the kernel mapped in every mode.
+    let boot_root = PhysPageNum(satp::read().ppn());
+    let mut tables = Vec::new();
+    let supported = PagingMode::ALL.map(|mode| {
+        let mut root = boot_root;
+        for _ in PagingMode::Sv39.levels()..mode.levels() {
+            let table = frame_alloc(FrameOwner::PageTable).unwrap();
+            table.ppn.get_pte_array()[511] = PageTableEntry::new(root, PTEFlags::V);
+            root = table.ppn;
+            tables.push(table);
+        }
+        mode.supported(root)
+    });
+    let mode = PagingMode::ALL
+        .into_iter()
+        .zip(supported)
+        .filter(|&(mode, supported)| supported && mode.levels() <= MAX_PAGING_LEVELS)
+        .map(|(mode, _)| mode)
+        .next_back()
+        .unwrap_or_else(|| {
+            println!("[kernel] satp accepts none of Sv39/Sv48/Sv57, assuming Sv39");
+            PagingMode::Sv39
+        });
+    PAGING_MODE.store(mode as usize, Ordering::Relaxed);
+    let platform = PLATFORM.read();
+    SVPBMT.store(platform.has_svpbmt, Ordering::Relaxed);
+    SVNAPOT.store(platform.has_svnapot, Ordering::Relaxed);
+    println!("[kernel] paging mode {:?} ({} levels)", mode, mode.levels());
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct PageTableEntry {
@@ -29,17 +158,21 @@ pub struct PageTableEntry {
 impl PageTableEntry {
     pub fn new(ppn: PhysPageNum, flags: PTEFlags) -> Self {
         PageTableEntry {
-            bits: ppn.0 << 10 | flags.bits() as usize,
+            bits: ppn.0 << 10 | flags.bits(),
         }
     }
     pub fn empty() -> Self {
         PageTableEntry { bits: 0 }
     }
     pub fn ppn(&self) -> PhysPageNum {
-        (self.bits >> 10 & ((1usize << 44) - 1)).into()
+        ((self.bits & PPN_MASK) >> 10).into()
     }
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
+        PTEFlags::from_bits_truncate(self.bits & !PPN_MASK)
+    }
+    /// Keep the PPN, replace everything else.
+    pub fn set_flags(&mut self, flags: PTEFlags) {
+        self.bits = (self.bits & PPN_MASK) | flags.bits();
     }
     pub fn is_valid(&self) -> bool {
         (self.flags() & PTEFlags::V) != PTEFlags::empty()
@@ -53,81 +186,227 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    /// Valid and R, W or X set; otherwise a valid entry points to the next table.
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && self.flags().intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
+    pub fn is_napot(&self) -> bool {
+        self.flags().contains(PTEFlags::N)
+    }
+}
+
+/// Pages mapped by one leaf at `level`.
+pub fn pages_at(level: usize) -> usize {
+    1 << (9 * level)
 }
 
 pub struct PageTable {
     root_ppn: PhysPageNum,
-    frames: Vec<FrameTracker>,
+    mode: PagingMode,
+    /// the tables, by ppn so that empty ones can be freed
+    frames: BTreeMap<PhysPageNum, FrameTracker>,
 }
 
 impl PageTable {
     pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
+        let frame = frame_alloc(FrameOwner::PageTable).unwrap();
         PageTable {
             root_ppn: frame.ppn,
-            frames: vec![frame],
+            mode: paging_mode(),
+            frames: BTreeMap::from([(frame.ppn, frame)]),
         }
     }
     /// Temporarily used to get arguments from user space.
     pub fn from_token(satp: usize) -> Self {
         Self {
             root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
-            frames: Vec::new(),
+            mode: PagingMode::from_satp(satp).expect("satp is not in a paging mode"),
+            frames: BTreeMap::new(),
         }
     }
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-        let idxs = vpn.indexes();
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+    /// The entry for `vpn` at `level`, creating the tables above it.
+    fn find_pte_create(&mut self, vpn: VirtPageNum, level: usize) -> &mut PageTableEntry {
         let mut ppn = self.root_ppn;
-        let mut result: Option<&mut PageTableEntry> = None;
-        for (i, idx) in idxs.iter().enumerate() {
-            let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
-                result = Some(pte);
-                break;
-            }
+        for l in (level + 1..self.mode.levels()).rev() {
+            let pte = &mut ppn.get_pte_array()[vpn.index(l)];
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc(FrameOwner::PageTable).unwrap();
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
-                self.frames.push(frame);
+                self.frames.insert(frame.ppn, frame);
             }
+            assert!(!pte.is_leaf(), "vpn {:?} is inside a huge page", vpn);
             ppn = pte.ppn();
         }
-        result
+        &mut ppn.get_pte_array()[vpn.index(level)]
     }
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
-        let idxs = vpn.indexes();
+    /// The leaf mapping `vpn` and its level, or the level-0 entry if
+    /// there is no leaf above it; `None` if a table on the way is missing.
+    fn find_pte(&self, vpn: VirtPageNum) -> Option<(&mut PageTableEntry, usize)> {
         let mut ppn = self.root_ppn;
-        let mut result: Option<&mut PageTableEntry> = None;
-        for (i, idx) in idxs.iter().enumerate() {
-            let pte = &mut ppn.get_pte_array()[*idx];
-            if i == 2 {
-                result = Some(pte);
-                break;
+        for level in (0..self.mode.levels()).rev() {
+            let pte = &mut ppn.get_pte_array()[vpn.index(level)];
+            if level == 0 || pte.is_leaf() {
+                return Some((pte, level));
             }
             if !pte.is_valid() {
                 return None;
             }
             ppn = pte.ppn();
         }
-        result
+        unreachable!()
     }
-    #[allow(unused)]
     pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+        self.map_huge(vpn, ppn, 0, flags);
+    }
+    /// Map `pages_at(level)` pages from `vpn` to `ppn` with a single leaf
+    /// at `level`: 2 MiB pages at level 1, 1 GiB pages at level 2.
+    pub fn map_huge(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, level: usize, flags: PTEFlags) {
+        assert!(level < self.mode.levels(), "no level {} in {:?}", level, self.mode);
+        let pages = pages_at(level);
+        assert!(
+            vpn.0.is_multiple_of(pages) && ppn.0.is_multiple_of(pages),
+            "{:?} -> {:?} is not aligned for a level-{} page",
+            vpn,
+            ppn,
+            level
+        );
+        let pte = self.find_pte_create(vpn, level);
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
-        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        *pte = PageTableEntry::new(ppn, supported(flags | PTEFlags::V));
+    }
+    /// Map a 64 KiB page with Svnapot, or as 16 plain pages without it.
+    pub fn map_napot(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        assert!(
+            vpn.0.is_multiple_of(NAPOT_PAGES) && ppn.0.is_multiple_of(NAPOT_PAGES),
+            "{:?} -> {:?} is not aligned for a 64 KiB page",
+            vpn,
+            ppn
+        );
+        let napot = SVNAPOT.load(Ordering::Relaxed);
+        let flags = supported(flags | PTEFlags::V);
+        for i in 0..NAPOT_PAGES {
+            let pte = self.find_pte_create(VirtPageNum(vpn.0 + i), 0);
+            assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", VirtPageNum(vpn.0 + i));
+            *pte = if napot {
+                // every entry has the same PPN, its low bits 0b1000 give the size
+                PageTableEntry::new(PhysPageNum(ppn.0 | (NAPOT_PAGES / 2)), flags | PTEFlags::N)
+            } else {
+                PageTableEntry::new(PhysPageNum(ppn.0 + i), flags)
+            };
+        }
+    }
+    /// Change the flags of the leaf mapping `vpn`, e.g. to mark it copy-on-write.
+    /// The 16 PTEs of a Svnapot page must stay identical, so they all change.
+    pub fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) {
+        let (pte, _) = self.find_pte(vpn).unwrap();
+        assert!(pte.is_leaf(), "vpn {:?} is not mapped", vpn);
+        let napot = pte.flags() & PTEFlags::N;
+        let pages = if pte.is_napot() { NAPOT_PAGES } else { 1 };
+        let first = vpn.0 & !(pages - 1);
+        for vpn in first..first + pages {
+            let (pte, _) = self.find_pte(VirtPageNum(vpn)).unwrap();
+            pte.set_flags(supported(flags | PTEFlags::V | napot));
+        }
     }
-    #[allow(unused)]
-    pub fn unmap(&mut self, vpn: VirtPageNum) {
-        let pte = self.find_pte(vpn).unwrap();
+    /// Remove the leaf mapping `vpn`, which must be the first page of a huge page.
+    /// Returns the tables left empty, as `unmap_range` does.
+    #[must_use]
+    pub fn unmap(&mut self, vpn: VirtPageNum) -> Vec<FrameTracker> {
+        let (pte, level) = self.find_pte(vpn).unwrap();
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
-        *pte = PageTableEntry::empty();
+        let pages = if pte.is_napot() { NAPOT_PAGES } else { pages_at(level) };
+        assert!(vpn.0.is_multiple_of(pages), "vpn {:?} is inside a huge page", vpn);
+        self.unmap_range(vpn, VirtPageNum(vpn.0 + pages))
+    }
+    /// Remove every mapping in [start, end) and take out the tables left
+    /// empty. Tables entirely inside the range go at once, without visiting
+    /// their pages. Huge pages must not straddle the ends of the range.
+    ///
+    /// The tables taken out are returned rather than freed: a TLB may still
+    /// cache entries pointing into them, so whoever flushes decides when
+    /// they can be reused.
+    #[must_use]
+    pub fn unmap_range(&mut self, start: VirtPageNum, end: VirtPageNum) -> Vec<FrameTracker> {
+        let mut freed = Vec::new();
+        if start < end {
+            self.unmap_table(self.root_ppn, self.mode.levels() - 1, 0, start.0, end.0, &mut freed);
+        }
+        freed
+    }
+    /// Unmap [start, end) in `table` at `level`, whose first entry maps vpn
+    /// `base`, adding the tables taken out to `freed`. Returns whether the
+    /// table is empty afterwards.
+    fn unmap_table(
+        &mut self,
+        table: PhysPageNum,
+        level: usize,
+        base: usize,
+        start: usize,
+        end: usize,
+        freed: &mut Vec<FrameTracker>,
+    ) -> bool {
+        let size = pages_at(level);
+        let first = start.saturating_sub(base) / size;
+        let last = ((end - 1 - base) / size).min(511);
+        let ptes = table.get_pte_array();
+        for (i, pte) in ptes.iter_mut().enumerate().take(last + 1).skip(first) {
+            if !pte.is_valid() {
+                continue;
+            }
+            let lo = base + i * size;
+            let covered = start <= lo && lo + size <= end;
+            if level == 0 || pte.is_leaf() {
+                let (page, pages) = match pte.is_napot() {
+                    true => (lo & !(NAPOT_PAGES - 1), NAPOT_PAGES),
+                    false => (lo, size),
+                };
+                assert!(
+                    start <= page && page + pages <= end,
+                    "unmapping part of the huge page at {:?}",
+                    VirtPageNum(page)
+                );
+            } else if covered {
+                self.free_table(pte.ppn(), level - 1, freed);
+            } else if self.unmap_table(pte.ppn(), level - 1, lo, start, end, freed) {
+                freed.extend(self.frames.remove(&pte.ppn()));
+            } else {
+                continue;
+            }
+            *pte = PageTableEntry::empty();
+        }
+        ptes.iter().all(|pte| !pte.is_valid())
     }
+    /// Take out `table` at `level` and every table below it.
+    fn free_table(&mut self, table: PhysPageNum, level: usize, freed: &mut Vec<FrameTracker>) {
+        if level > 0 {
+            for pte in table.get_pte_array().iter().filter(|pte| pte.is_valid() && !pte.is_leaf()) {
+                self.free_table(pte.ppn(), level - 1, freed);
+            }
+        }
+        freed.extend(self.frames.remove(&table));
+    }
+    /// Number of frames holding this table, the root included.
+    pub fn table_frames(&self) -> usize {
+        self.frames.len()
+    }
+    /// The entry mapping `vpn`; for a huge page, with the ppn of that very page.
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).map(|pte| *pte)
+        self.find_pte(vpn).map(|(pte, level)| {
+            let mut pte = *pte;
+            if pte.is_napot() {
+                let ppn = (pte.ppn().0 & !(NAPOT_PAGES - 1)) | (vpn.0 % NAPOT_PAGES);
+                pte = PageTableEntry::new(PhysPageNum(ppn), pte.flags());
+            } else if pte.is_valid() {
+                pte.bits += (vpn.0 % pages_at(level)) << 10;
+            }
+            pte
+        })
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        self.find_pte(va.clone().floor()).map(|pte| {
+        self.translate(va.clone().floor()).map(|pte| {
             let aligned_pa: PhysAddr = pte.ppn().into();
             let offset = va.page_offset();
             let aligned_pa_usize: usize = aligned_pa.into();
@@ -135,7 +414,7 @@ impl PageTable {
         })
     }
     pub fn token(&self) -> usize {
-        8usize << 60 | self.root_ppn.0
+        (self.mode as usize) << 60 | self.root_ppn.0
     }
 }
 
@@ -201,6 +480,7 @@ pub struct UserBuffer {
     pub buffers: Vec<&'static mut [u8]>,
 }
 
+#[allow(unused)]
 impl UserBuffer {
     pub fn new(buffers: Vec<&'static mut [u8]>) -> Self {
         Self { buffers }
@@ -248,4 +528,89 @@ impl Iterator for UserBufferIterator {
             Some(r)
         }
     }
-}
\ No newline at end of file
+}
+
+#[allow(unused)]
+pub fn page_table_test() {
+    let mut page_table = PageTable::new();
+    let frame = frame_alloc(FrameOwner::Other).unwrap();
+    // the highest user page this paging mode can address
+    let top = VirtPageNum((1 << (page_table.mode().va_bits() - 1 - 12)) - 1);
+    for vpn in [VirtPageNum(0x12345), top] {
+        page_table.map(vpn, frame.ppn, PTEFlags::R | PTEFlags::W);
+        let pte = page_table.translate(vpn).unwrap();
+        assert_eq!(pte.ppn(), frame.ppn);
+        assert!(pte.readable() && pte.writable() && !pte.executable());
+        // never activated, so no TLB has seen its tables
+        drop(page_table.unmap(vpn));
+        assert!(!page_table.translate(vpn).unwrap().is_valid());
+    }
+    // a 2 MiB page, translated page by page
+    let (vpn, ppn) = (VirtPageNum(0x4_0000), PhysPageNum(0x8_0200));
+    page_table.map_huge(vpn, ppn, 1, PTEFlags::R);
+    for i in [0, 1, 511] {
+        let pte = page_table.translate(VirtPageNum(vpn.0 + i)).unwrap();
+        assert!(pte.is_leaf());
+        assert_eq!(pte.ppn(), PhysPageNum(ppn.0 + i));
+    }
+    drop(page_table.unmap(vpn));
+    assert!(!page_table.translate(vpn).unwrap().is_valid());
+    // software bits and memory types survive, the PPN is untouched
+    let vpn = VirtPageNum(0x5_0000);
+    page_table.map(vpn, frame.ppn, PTEFlags::R | PTEFlags::U | PTEFlags::COW | PTEFlags::IO);
+    let flags = page_table.translate(vpn).unwrap().flags();
+    assert!(flags.contains(PTEFlags::COW) && flags.contains(PTEFlags::U));
+    assert_eq!(flags.contains(PTEFlags::IO), PLATFORM.read().has_svpbmt);
+    page_table.set_flags(vpn, PTEFlags::R | PTEFlags::W | PTEFlags::U);
+    let pte = page_table.translate(vpn).unwrap();
+    assert!(pte.writable() && !pte.flags().contains(PTEFlags::COW) && pte.ppn() == frame.ppn);
+    drop(page_table.unmap(vpn));
+    // a 64 KiB page, with or without Svnapot
+    let (vpn, ppn) = (VirtPageNum(0x6_0010), PhysPageNum(0x8_0030));
+    page_table.map_napot(vpn, ppn, PTEFlags::R);
+    for i in [0, 7, 15] {
+        assert_eq!(page_table.translate(VirtPageNum(vpn.0 + i)).unwrap().ppn(), PhysPageNum(ppn.0 + i));
+    }
+    // a Svnapot page changes as a whole, plain pages one at a time
+    let napot = page_table.translate(vpn).unwrap().is_napot();
+    page_table.set_flags(VirtPageNum(vpn.0 + 7), PTEFlags::R | PTEFlags::W);
+    for i in 0..NAPOT_PAGES {
+        let pte = page_table.translate(VirtPageNum(vpn.0 + i)).unwrap();
+        assert_eq!(pte.writable(), napot || i == 7);
+        assert_eq!(pte.ppn(), PhysPageNum(ppn.0 + i));
+    }
+    drop(page_table.unmap(vpn));
+    assert!(!page_table.translate(VirtPageNum(vpn.0 + 15)).unwrap().is_valid());
+    assert_eq!(PageTable::from_token(page_table.token()).mode(), page_table.mode());
+    println!("page_table_test passed! ({:?})", page_table.mode());
+}
+
+#[allow(unused)]
+pub fn page_table_teardown_test() {
+    use super::frame_allocator::frame_counts;
+    let (free, _) = frame_counts();
+    let mut page_table = PageTable::new();
+    let frame = frame_alloc(FrameOwner::Other).unwrap();
+    // spread over several leaf tables, a huge page, and a far-away page
+    let start = VirtPageNum(0x1_0000 - 100);
+    let end = VirtPageNum(start.0 + 3 * 512);
+    for vpn in start.0..end.0 {
+        page_table.map(VirtPageNum(vpn), frame.ppn, PTEFlags::R);
+    }
+    page_table.map_huge(VirtPageNum(0x2_0000), PhysPageNum(0x8_0000), 1, PTEFlags::R);
+    page_table.map(VirtPageNum(0x300_0000), frame.ppn, PTEFlags::R);
+    assert!(page_table.table_frames() > 5);
+    // partly: the leaf tables in the middle stay; the table was never
+    // activated, so the tables taken out can be freed at once
+    drop(page_table.unmap_range(VirtPageNum(start.0 + 10), VirtPageNum(end.0 - 10)));
+    assert!(page_table.translate(VirtPageNum(start.0 + 9)).unwrap().is_valid());
+    assert!(page_table.translate(VirtPageNum(start.0 + 10)).is_none_or(|pte| !pte.is_valid()));
+    drop(page_table.unmap(VirtPageNum(0x300_0000)));
+    drop(page_table.unmap_range(VirtPageNum(0), VirtPageNum(0x4_0000)));
+    assert_eq!(page_table.table_frames(), 1, "empty tables were not freed");
+    drop(frame);
+    assert_eq!(frame_counts().0, free - 1);
+    drop(page_table);
+    assert_eq!(frame_counts().0, free, "map/unmap leaked frames");
+    println!("page_table_teardown_test passed!");
+}
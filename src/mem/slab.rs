@@ -0,0 +1,385 @@
+//! Object caches for fixed-size kernel objects.
+//!
+//! A `KmemCache` hands out objects of one size and alignment from slabs:
+//! runs of `1 << order` frames, aligned to their size, with a header at the
+//! start and the objects behind it. Free objects are chained through their
+//! first word. A slab is found from any of its objects by rounding the
+//! address down, so freeing needs no lookup. Slabs move between the
+//! partial, full and empty lists as objects come and go; one empty slab is
+//! kept around, any further ones go back to the frame allocator.
+//!
+//! With `debug()`, every object is surrounded by red zones that are checked
+//! on free, and free objects are poisoned and checked on the next alloc.
+
+use core::fmt;
+use core::ptr::{null_mut, NonNull};
+use crate::config::PAGE_SIZE;
+use crate::println;
+use crate::sync::SpinLock;
+use super::frame_allocator::{frame_alloc_contiguous, ContiguousFrames, FrameOwner};
+
+/// Fill of a free object, checked when it is handed out again
+const POISON_FREE: u8 = 0x6b;
+/// Fill of the bytes around an object
+const RED_ZONE: u8 = 0xbb;
+/// A slab should hold at least this many objects...
+const MIN_OBJECTS: usize = 8;
+/// ...unless that takes more than `1 << MAX_SLAB_ORDER` frames.
+const MAX_SLAB_ORDER: usize = 4;
+
+struct Slab {
+    /// the frames this header lives in, freed with the slab
+    frames: ContiguousFrames,
+    prev: *mut Slab,
+    next: *mut Slab,
+    /// first free object, linked through their first word
+    free: *mut u8,
+    in_use: usize,
+}
+
+struct SlabLists {
+    partial: *mut Slab,
+    full: *mut Slab,
+    empty: *mut Slab,
+    slabs: usize,
+    in_use: usize,
+    allocs: usize,
+    frees: usize,
+}
+
+// the slabs are only reached through the cache's lock
+unsafe impl Send for SlabLists {}
+
+pub struct KmemCache {
+    name: &'static str,
+    size: usize,
+    align: usize,
+    debug: bool,
+    /// distance between objects
+    stride: usize,
+    /// start of the object in its slot, after the leading red zone
+    object_offset: usize,
+    /// offset of the first slot in a slab
+    first_offset: usize,
+    order: usize,
+    objects_per_slab: usize,
+    lists: SpinLock<SlabLists>,
+}
+
+/// Per-cache counters, in objects unless noted.
+#[derive(Copy, Clone, Debug)]
+pub struct CacheStats {
+    pub name: &'static str,
+    /// in bytes, as requested
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub total: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+impl KmemCache {
+    /// A cache of objects of `size` bytes aligned to `align`, a power of two.
+    #[track_caller]
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "KmemCache: alignment is not a power of two");
+        assert!(align <= PAGE_SIZE, "KmemCache: alignment larger than a page");
+        let mut cache = Self {
+            name,
+            size,
+            align,
+            debug: false,
+            stride: 0,
+            object_offset: 0,
+            first_offset: 0,
+            order: 0,
+            objects_per_slab: 0,
+            lists: SpinLock::new(SlabLists {
+                partial: null_mut(),
+                full: null_mut(),
+                empty: null_mut(),
+                slabs: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            })
+            .named(name),
+        };
+        cache.layout();
+        cache
+    }
+
+    /// Red zones around objects and poison in free ones.
+    pub const fn debug(mut self) -> Self {
+        self.debug = true;
+        self.layout();
+        self
+    }
+
+    const fn layout(&mut self) {
+        // room for the free-list link, kept aligned
+        let align = if self.align > 8 { self.align } else { 8 };
+        let size = if self.size > 8 { self.size } else { 8 };
+        let size = size.next_multiple_of(align);
+        let red_zone = if self.debug { align } else { 0 };
+        self.stride = red_zone + size + red_zone;
+        self.object_offset = red_zone;
+        self.first_offset = size_of::<Slab>().next_multiple_of(align);
+        self.order = 0;
+        while self.order < MAX_SLAB_ORDER && self.capacity(self.order) < MIN_OBJECTS {
+            self.order += 1;
+        }
+        self.objects_per_slab = self.capacity(self.order);
+        assert!(self.objects_per_slab > 0, "KmemCache: object too large for a slab");
+    }
+
+    const fn capacity(&self, order: usize) -> usize {
+        let bytes = PAGE_SIZE << order;
+        if bytes < self.first_offset { 0 } else { (bytes - self.first_offset) / self.stride }
+    }
+
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    fn slot(&self, slab: *mut Slab, index: usize) -> *mut u8 {
+        (slab as usize + self.first_offset + index * self.stride) as *mut u8
+    }
+
+    /// Take frames for a new slab and thread all of its objects onto its free list.
+    fn new_slab(&self) -> Option<*mut Slab> {
+        let frames = frame_alloc_contiguous(self.order, FrameOwner::Slab)?;
+        let slab = frames.ppn.get_bytes_array().as_mut_ptr() as *mut Slab;
+        let mut free = null_mut();
+        for index in (0..self.objects_per_slab).rev() {
+            let slot = self.slot(slab, index);
+            let object = unsafe { slot.add(self.object_offset) };
+            if self.debug {
+                unsafe {
+                    slot.write_bytes(RED_ZONE, self.stride);
+                    object.write_bytes(POISON_FREE, self.stride - 2 * self.object_offset);
+                }
+            }
+            unsafe { (object as *mut *mut u8).write(free) };
+            free = object;
+        }
+        unsafe {
+            slab.write(Slab {
+                frames,
+                prev: null_mut(),
+                next: null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+        Some(slab)
+    }
+
+    fn list<'a>(&self, lists: &'a mut SlabLists, in_use: usize) -> &'a mut *mut Slab {
+        match in_use {
+            0 => &mut lists.empty,
+            n if n == self.objects_per_slab => &mut lists.full,
+            _ => &mut lists.partial,
+        }
+    }
+
+    pub fn alloc(&self) -> Option<NonNull<u8>> {
+        let mut lists = self.lists.lock();
+        let slab = if !lists.partial.is_null() {
+            lists.partial
+        } else if !lists.empty.is_null() {
+            lists.empty
+        } else {
+            let slab = self.new_slab()?;
+            lists.slabs += 1;
+            unsafe { push(&mut lists.empty, slab) };
+            slab
+        };
+        let slab_ref = unsafe { &mut *slab };
+        let object = slab_ref.free;
+        if self.debug {
+            self.check_poison(object);
+        }
+        slab_ref.free = unsafe { (object as *mut *mut u8).read() };
+        unsafe { unlink(self.list(&mut lists, slab_ref.in_use), slab) };
+        slab_ref.in_use += 1;
+        unsafe { push(self.list(&mut lists, slab_ref.in_use), slab) };
+        lists.in_use += 1;
+        lists.allocs += 1;
+        NonNull::new(object)
+    }
+
+    /// Return an object to the cache.
+    ///
+    /// # Safety
+    /// `object` must come from `alloc` on this cache and not be used afterwards.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let object = object.as_ptr();
+        let slab = (object as usize & !(self.slab_bytes() - 1)) as *mut Slab;
+        let offset = object as usize - slab as usize;
+        let index = offset.wrapping_sub(self.first_offset + self.object_offset) / self.stride;
+        if index >= self.objects_per_slab || self.slot(slab, index) as usize + self.object_offset != object as usize {
+            panic!("KmemCache {}: freeing {:p}, which is not one of its objects", self.name, object);
+        }
+        let mut lists = self.lists.lock();
+        let slab_ref = unsafe { &mut *slab };
+        assert!(slab_ref.in_use > 0, "KmemCache {}: double free of {:p}", self.name, object);
+        if self.debug {
+            self.check_red_zones(object);
+            let mut free = slab_ref.free;
+            while !free.is_null() {
+                assert!(free != object, "KmemCache {}: double free of {:p}", self.name, object);
+                free = unsafe { (free as *mut *mut u8).read() };
+            }
+            unsafe { object.write_bytes(POISON_FREE, self.stride - 2 * self.object_offset) };
+        }
+        unsafe { (object as *mut *mut u8).write(slab_ref.free) };
+        slab_ref.free = object;
+        unsafe { unlink(self.list(&mut lists, slab_ref.in_use), slab) };
+        slab_ref.in_use -= 1;
+        lists.in_use -= 1;
+        lists.frees += 1;
+        if slab_ref.in_use == 0 && !lists.empty.is_null() {
+            // one empty slab is enough
+            lists.slabs -= 1;
+            drop(unsafe { slab.read() }.frames);
+        } else {
+            unsafe { push(self.list(&mut lists, slab_ref.in_use), slab) };
+        }
+    }
+
+    /// Give every empty slab back to the frame allocator.
+    pub fn shrink(&self) {
+        let mut lists = self.lists.lock();
+        while !lists.empty.is_null() {
+            let slab = lists.empty;
+            unsafe { unlink(&mut lists.empty, slab) };
+            lists.slabs -= 1;
+            drop(unsafe { slab.read() }.frames);
+        }
+    }
+
+    fn check_poison(&self, object: *mut u8) {
+        let len = self.stride - 2 * self.object_offset;
+        // the first word is the free-list link
+        let bytes = unsafe { core::slice::from_raw_parts(object, len) };
+        if let Some(at) = bytes[size_of::<usize>()..].iter().position(|&byte| byte != POISON_FREE) {
+            panic!(
+                "KmemCache {}: free object {:p} was written at offset {}",
+                self.name,
+                object,
+                at + size_of::<usize>()
+            );
+        }
+    }
+
+    fn check_red_zones(&self, object: *mut u8) {
+        let zone = self.object_offset;
+        let before = unsafe { core::slice::from_raw_parts(object.sub(zone), zone) };
+        let after = unsafe { core::slice::from_raw_parts(object.add(self.stride - 2 * zone), zone) };
+        if before.iter().chain(after).any(|&byte| byte != RED_ZONE) {
+            panic!("KmemCache {}: red zone around {:p} overwritten", self.name, object);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lists = self.lists.lock();
+        CacheStats {
+            name: self.name,
+            object_size: self.size,
+            objects_per_slab: self.objects_per_slab,
+            slabs: lists.slabs,
+            in_use: lists.in_use,
+            total: lists.slabs * self.objects_per_slab,
+            allocs: lists.allocs,
+            frees: lists.frees,
+        }
+    }
+}
+
+impl Drop for KmemCache {
+    fn drop(&mut self) {
+        assert_eq!(self.lists.lock().in_use, 0, "KmemCache {} dropped with live objects", self.name);
+        self.shrink();
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:>6} B x {:>3}/slab: {} slabs, {}/{} in use, {} allocs, {} frees",
+            self.name,
+            self.object_size,
+            self.objects_per_slab,
+            self.slabs,
+            self.in_use,
+            self.total,
+            self.allocs,
+            self.frees
+        )
+    }
+}
+
+unsafe fn push(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        (*slab).prev = null_mut();
+        (*slab).next = *head;
+        if !head.is_null() {
+            (**head).prev = slab;
+        }
+    }
+    *head = slab;
+}
+
+unsafe fn unlink(head: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        let (prev, next) = ((*slab).prev, (*slab).next);
+        if prev.is_null() {
+            *head = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn slab_test() {
+    use alloc::vec::Vec;
+    let cache = KmemCache::new("slab_test", 24, 16).debug();
+    let mut objects = Vec::new();
+    for i in 0..200 {
+        let Some(object) = cache.alloc() else {
+            println!("slab_test skipped: no contiguous allocations");
+            return;
+        };
+        assert_eq!(object.as_ptr() as usize % 16, 0, "misaligned object");
+        unsafe { object.as_ptr().write_bytes(i as u8, 24) };
+        objects.push(object);
+    }
+    for (i, object) in objects.iter().enumerate() {
+        let bytes = unsafe { core::slice::from_raw_parts(object.as_ptr(), 24) };
+        assert!(bytes.iter().all(|&byte| byte == i as u8), "objects overlap");
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.in_use, 200);
+    assert!(stats.total >= 200 && stats.slabs == stats.total / stats.objects_per_slab);
+    println!("{}", stats);
+    for object in objects.drain(..) {
+        unsafe { cache.free(object) };
+    }
+    let stats = cache.stats();
+    assert_eq!((stats.in_use, stats.allocs, stats.frees), (0, 200, 200));
+    assert_eq!(stats.slabs, 1, "empty slabs were not released");
+    // freed objects come back poisoned and pass the check
+    let object = cache.alloc().unwrap();
+    unsafe { cache.free(object) };
+    cache.shrink();
+    assert_eq!(cache.stats().slabs, 0);
+    println!("slab_test passed!");
+}
@@ -0,0 +1,302 @@
+//! Machine description, discovered from the device tree passed in `a1`.
+//! Without a usable device tree we assume QEMU virt with 128 MiB of RAM.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use crate::config::{CLOCK_FREQ, DIRECT_MAP_SIZE, MAX_HARTS, MEMORY_END};
+use crate::fdt::{Fdt, FdtError, Node};
+use crate::mem::phys_to_virt;
+use crate::println;
+use crate::sync::RwSpinLock;
+
+/// Interrupt cause of the S-mode external interrupt in `interrupts-extended`
+const S_EXTERNAL_CAUSE: u32 = 9;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemRegion {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl MemRegion {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    /// Interrupt source number at the PLIC
+    pub irq: Option<usize>,
+}
+
+pub struct Platform {
+    pub memory: Vec<MemRegion>,
+    /// `/memreserve/` entries, `/reserved-memory` children and the DTB itself
+    pub reserved: Vec<MemRegion>,
+    pub uart: Option<MmioDevice>,
+    pub clint: Option<MmioDevice>,
+    pub plic: Option<MmioDevice>,
+    /// Number of interrupt sources the PLIC supports
+    pub plic_ndev: usize,
+    /// (hartid, PLIC context) for the S-mode external interrupt of each hart
+    pub plic_contexts: Vec<(usize, usize)>,
+    pub virtio_mmio: Vec<MmioDevice>,
+    /// SiFive test device, used by the firmware for shutdown
+    pub test: Option<MmioDevice>,
+    pub hart_ids: Vec<usize>,
+    pub timebase_frequency: usize,
+    /// Every hart implements Sstc, so S-mode can program `stimecmp` itself
+    pub has_sstc: bool,
+    /// Every hart implements Svpbmt, so PTEs may select the memory type
+    pub has_svpbmt: bool,
+    /// Every hart implements Svnapot, so 64 KiB pages are available
+    pub has_svnapot: bool,
+}
+
+lazy_static! {
+    pub static ref PLATFORM: RwSpinLock<Platform> = RwSpinLock::new(Platform::qemu_virt()).named("PLATFORM");
+}
+
+impl Platform {
+    pub fn qemu_virt() -> Self {
+        let mmio = |base, size, irq| Some(MmioDevice { base, size, irq });
+        Self {
+            memory: vec![MemRegion { start: 0x8000_0000, size: MEMORY_END - 0x8000_0000 }],
+            reserved: Vec::new(),
+            uart: mmio(0x1000_0000, 0x100, Some(10)),
+            clint: mmio(0x200_0000, 0x1_0000, None),
+            plic: mmio(0xc00_0000, 0x60_0000, None),
+            plic_ndev: 95,
+            // QEMU virt: an M-mode and an S-mode context per hart
+            plic_contexts: (0..MAX_HARTS).map(|hartid| (hartid, 2 * hartid + 1)).collect(),
+            virtio_mmio: (0..8)
+                .map(|i| MmioDevice { base: 0x1000_1000 + i * 0x1000, size: 0x1000, irq: Some(1 + i) })
+                .collect(),
+            test: mmio(0x10_0000, 0x1000, None),
+            hart_ids: vec![0],
+            timebase_frequency: CLOCK_FREQ,
+            has_sstc: false,
+            has_svpbmt: false,
+            has_svnapot: false,
+        }
+    }
+
+    pub fn from_fdt(fdt: &Fdt, dtb_pa: usize) -> Self {
+        let mut platform = Self {
+            memory: Vec::new(),
+            reserved: vec![MemRegion { start: dtb_pa, size: fdt.total_size() }],
+            uart: None,
+            clint: None,
+            plic: None,
+            plic_ndev: 0,
+            plic_contexts: Vec::new(),
+            virtio_mmio: Vec::new(),
+            test: None,
+            hart_ids: Vec::new(),
+            timebase_frequency: CLOCK_FREQ,
+            has_sstc: true,
+            has_svpbmt: true,
+            has_svnapot: true,
+        };
+        for (start, size) in fdt.reservations() {
+            platform.reserved.push(MemRegion { start: start as usize, size: size as usize });
+        }
+        let mut reserved_memory_depth = None;
+        // a disabled node, skipped along with everything below it
+        let mut disabled_depth = None;
+        // the cpu node being walked, as (depth, hartid)
+        let mut cpu = None;
+        // phandle of each hart's interrupt controller, as (phandle, hartid)
+        let mut cpu_intcs = Vec::new();
+        let mut plic_interrupts = None;
+        for node in fdt.nodes() {
+            if cpu.is_some_and(|(depth, _)| node.depth <= depth) {
+                cpu = None;
+            }
+            if disabled_depth.is_some_and(|depth| node.depth <= depth) {
+                disabled_depth = None;
+            }
+            if disabled_depth.is_some() {
+                continue;
+            }
+            if reserved_memory_depth.is_some_and(|depth| node.depth <= depth) {
+                reserved_memory_depth = None;
+            }
+            if let Some(depth) = reserved_memory_depth {
+                if node.depth == depth + 1 {
+                    platform.reserved.extend(regions(&node));
+                }
+                continue;
+            }
+            if node.depth == 1 && node.base_name() == "reserved-memory" {
+                reserved_memory_depth = Some(node.depth);
+                continue;
+            }
+            if !node.is_enabled() {
+                disabled_depth = Some(node.depth);
+                continue;
+            }
+            if node.depth == 1
+                && node.name == "cpus"
+                && let Some(freq) = node.property_usize("timebase-frequency")
+            {
+                platform.timebase_frequency = freq;
+            }
+            match node.device_type() {
+                Some("memory") => platform.memory.extend(regions(&node)),
+                Some("cpu") => {
+                    if let Some((hartid, _)) = node.reg().next() {
+                        platform.hart_ids.push(hartid);
+                        platform.has_sstc &= node.has_isa_extension("sstc");
+                        platform.has_svpbmt &= node.has_isa_extension("svpbmt");
+                        platform.has_svnapot &= node.has_isa_extension("svnapot");
+                        cpu = Some((node.depth, hartid));
+                    }
+                }
+                _ => {}
+            }
+            if let Some((_, hartid)) = cpu
+                && node.is_compatible("riscv,cpu-intc")
+            {
+                cpu_intcs.extend(node.property_u32("phandle").map(|phandle| (phandle, hartid)));
+            }
+            let compatible = |names: &[&str]| names.iter().any(|c| node.is_compatible(c));
+            if compatible(&["ns16550a", "ns16550"]) && platform.uart.is_none() {
+                platform.uart = mmio_device(&node);
+            } else if compatible(&["riscv,clint0", "sifive,clint0"]) {
+                platform.clint = mmio_device(&node);
+            } else if compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+                platform.plic = mmio_device(&node);
+                platform.plic_ndev = node.property_u32("riscv,ndev").unwrap_or(0) as usize;
+                plic_interrupts = node.property("interrupts-extended");
+            } else if compatible(&["virtio,mmio"]) {
+                platform.virtio_mmio.extend(mmio_device(&node));
+            } else if compatible(&["sifive,test0", "sifive,test1"]) {
+                platform.test = mmio_device(&node);
+            }
+        }
+        // Context `i` of the PLIC is the i-th (phandle, cause) pair of
+        // `interrupts-extended`; cause 9 is the S-mode external interrupt.
+        for (context, pair) in plic_interrupts.unwrap_or(&[]).chunks_exact(8).enumerate() {
+            let cell = |i: usize| u32::from_be_bytes(pair[i * 4..i * 4 + 4].try_into().unwrap());
+            if cell(1) != S_EXTERNAL_CAUSE {
+                continue;
+            }
+            if let Some(&(_, hartid)) = cpu_intcs.iter().find(|(phandle, _)| *phandle == cell(0)) {
+                platform.plic_contexts.push((hartid, context));
+            }
+        }
+        platform.has_sstc &= !platform.hart_ids.is_empty();
+        platform.has_svpbmt &= !platform.hart_ids.is_empty();
+        platform.has_svnapot &= !platform.hart_ids.is_empty();
+        platform.memory.sort_by_key(|region| region.start);
+        platform.virtio_mmio.sort_by_key(|device| device.base);
+        platform.hart_ids.sort();
+        platform
+    }
+
+    /// Free physical memory above `kernel_end`: memory regions minus the
+    /// kernel image, every reserved region and what the direct map doesn't
+    /// reach, as sorted [start, end) pairs.
+    pub fn free_ranges(&self, kernel_end: usize) -> Vec<(usize, usize)> {
+        let mut holes: Vec<(usize, usize)> = self
+            .reserved
+            .iter()
+            .map(|region| (region.start, region.end()))
+            .collect();
+        holes.push((0, kernel_end));
+        holes.push((DIRECT_MAP_SIZE, usize::MAX));
+        holes.sort();
+        let mut ranges = Vec::new();
+        for region in self.memory.iter() {
+            let mut start = region.start;
+            for &(hole_start, hole_end) in holes.iter() {
+                if hole_end <= start || hole_start >= region.end() {
+                    continue;
+                }
+                if hole_start > start {
+                    ranges.push((start, hole_start));
+                }
+                start = start.max(hole_end);
+            }
+            if start < region.end() {
+                ranges.push((start, region.end()));
+            }
+        }
+        ranges
+    }
+
+    /// The UART, CLINT, PLIC, virtio and test devices, sorted by base.
+    pub fn mmio_devices(&self) -> Vec<MmioDevice> {
+        let mut devices: Vec<MmioDevice> = [self.uart, self.clint, self.plic, self.test]
+            .into_iter()
+            .flatten()
+            .chain(self.virtio_mmio.iter().copied())
+            .collect();
+        devices.sort_by_key(|device| device.base);
+        devices
+    }
+
+    pub fn memory_end(&self) -> usize {
+        self.memory.iter().map(|region| region.end()).max().unwrap_or(MEMORY_END)
+    }
+}
+
+fn regions(node: &Node) -> impl Iterator<Item = MemRegion> {
+    node.reg()
+        .filter(|&(_, size)| size != 0)
+        .map(|(start, size)| MemRegion { start, size })
+}
+
+fn mmio_device(node: &Node) -> Option<MmioDevice> {
+    let (base, size) = node.reg().next()?;
+    Some(MmioDevice { base, size, irq: node.interrupt() })
+}
+
+/// Parse the device tree at `dtb_pa`, keeping the QEMU virt defaults if it
+/// is missing or malformed.
+pub fn init(dtb_pa: usize) -> Result<(), FdtError> {
+    if dtb_pa == 0 {
+        return Err(FdtError::BadMagic);
+    }
+    let fdt = unsafe { Fdt::from_addr(phys_to_virt(dtb_pa)) }?;
+    let platform = Platform::from_fdt(&fdt, dtb_pa);
+    if platform.memory.is_empty() {
+        return Err(FdtError::Truncated);
+    }
+    *PLATFORM.write() = platform;
+    Ok(())
+}
+
+#[allow(unused)]
+pub fn memory_end() -> usize {
+    PLATFORM.read().memory_end()
+}
+
+pub fn print_info() {
+    let platform = PLATFORM.read();
+    for region in platform.memory.iter() {
+        println!("[kernel] memory [{:#x}, {:#x})", region.start, region.end());
+    }
+    for region in platform.reserved.iter() {
+        println!("[kernel] reserved [{:#x}, {:#x})", region.start, region.end());
+    }
+    println!(
+        "[kernel] {} hart(s) {:?}, timebase {} Hz{}{}{}",
+        platform.hart_ids.len(),
+        platform.hart_ids,
+        platform.timebase_frequency,
+        if platform.has_sstc { ", Sstc" } else { "" },
+        if platform.has_svpbmt { ", Svpbmt" } else { "" },
+        if platform.has_svnapot { ", Svnapot" } else { "" }
+    );
+    let devices = [("uart", platform.uart), ("clint", platform.clint), ("plic", platform.plic)];
+    for (name, device) in devices.iter().filter_map(|(name, d)| Some((name, (*d)?))) {
+        println!("[kernel] {} at {:#x}, irq {:?}", name, device.base, device.irq);
+    }
+    println!("[kernel] {} virtio-mmio slot(s)", platform.virtio_mmio.len());
+}
@@ -1,234 +1,198 @@
+//! S-mode client of the Supervisor Binary Interface.
+//!
+//! The kernel always reaches M-mode through these calls, whether the SBI
+//! implementation is the in-tree firmware (`-bios none`) or OpenSBI.
 #![allow(dead_code)]
 #![allow(unused)]
 
-use core::sync::atomic::{AtomicU8, Ordering};
-use core::hint::spin_loop;
-use volatile::access::{ReadOnly, ReadWrite};
-use volatile::{VolatileFieldAccess, VolatileRef};
-use core::fmt::{self, Write};
-
-// Hardcoded UART base address for QEMU virt
-const UART_BASE: usize = 0x10000000;
-const SHUTDOWN_BASE: usize = 0x100000;
-
-const BS: u8 = 0x8;  // Backspace
-const DEL: u8 = 0x7F; // Delete
-const SHUTDOWN_PASS: u16 = 0x5555;
-const SHUTDOWN_FAIL: u16 = 0x3333;
-
-/// Read port when DLAB = 0.
-#[repr(C)]
-#[derive(VolatileFieldAccess, Default)]
-struct ReadPort {
-    /// receive buffer
-    rbr: AtomicU8,
-    /// interrupt enable
-    ier: u8,
-    /// interrupt identification
-    #[access(ReadOnly)]
-    iir: u8,
-    /// line control
-    lcr: u8,
-    /// modem control
-    mcr: u8,
-    /// line status
-    lsr: AtomicU8,
-    /// modem status
-    msr: u8,
-    // scratch
-    scr: u8,
-}
-
-/// Write port when DLAB = 0.
-#[repr(C)]
-#[derive(VolatileFieldAccess, Default)]
-struct WritePort {
-    /// transmitter holding
-    thr: AtomicU8,
-    /// interrupt enable
-    ier: u8,
-    /// FIFO control
-    fcr: u8,
-    /// line control
-    lcr: u8,
-    /// modem control
-    mcr: u8,
-    /// line status
-    lsr: AtomicU8,
-    /// not used
-    #[access(ReadOnly)]
-    _padding: u8,
-    // scratch
-    #[access(ReadOnly)]
-    scr: u8,
-}
-
-// Constants for register bit flags
-mod flags {
-    // Interrupt Enable register flags
-    pub const IER_RX_AVAILABLE: u8 = 1 << 0;
-    pub const IER_TX_EMPTY: u8 = 1 << 1;
-
-    // FIFO Control register flags
-    pub const FCR_ENABLE: u8 = 1 << 0;
-    pub const FCR_CLEAR_RX_FIFO: u8 = 1 << 1;
-    pub const FCR_CLEAR_TX_FIFO: u8 = 1 << 2;
-    pub const FCR_TRIGGER_14: u8 = 0b11 << 6;
-
-    // Line Control register flags
-    pub const LCR_DATA_8: u8 = 0b11;
-    pub const LCR_DLAB_ENABLE: u8 = 1 << 7;
-
-    // Modem Control register flags
-    pub const MCR_DATA_TERMINAL_READY: u8 = 1 << 0;
-    pub const MCR_AUXILIARY_OUTPUT_2: u8 = 1 << 3;
-
-    // Line Status register flags
-    pub const LSR_INPUT_AVAILABLE: u8 = 1 << 0;
-    pub const LSR_OUTPUT_EMPTY: u8 = 1 << 5;
-}
-
-/// Simple UART driver
-pub struct Uart;
-
-impl Uart {
-    /// Get a reference to the read port
-    fn read_port(&self) -> &'static mut ReadPort {
-        unsafe { &mut *(UART_BASE as *mut ReadPort) }
-    }
+use core::arch::asm;
+use crate::mem::virt_to_phys;
+
+/// Extension IDs
+pub const EID_LEGACY_SHUTDOWN: usize = 0x08;
+pub const EID_BASE: usize = 0x10;
+pub const EID_TIME: usize = 0x5449_4D45;
+pub const EID_IPI: usize = 0x73_5049;
+pub const EID_RFENCE: usize = 0x5246_4E43;
+pub const EID_HSM: usize = 0x48_534D;
+pub const EID_SRST: usize = 0x5352_5354;
+pub const EID_DBCN: usize = 0x4442_434E;
+
+/// Function IDs of the Base extension
+pub const BASE_GET_SPEC_VERSION: usize = 0;
+pub const BASE_GET_IMPL_ID: usize = 1;
+pub const BASE_GET_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_GET_MVENDORID: usize = 4;
+pub const BASE_GET_MARCHID: usize = 5;
+pub const BASE_GET_MIMPID: usize = 6;
+
+/// Function IDs of the TIME extension
+pub const TIME_SET_TIMER: usize = 0;
+
+/// Function IDs of the IPI extension
+pub const IPI_SEND_IPI: usize = 0;
+
+/// Function IDs of the RFENCE extension
+pub const RFENCE_REMOTE_FENCE_I: usize = 0;
+pub const RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+pub const RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+/// Function IDs of the HSM extension
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+pub const HSM_HART_GET_STATUS: usize = 2;
+pub const HSM_HART_SUSPEND: usize = 3;
+
+/// Hart states reported by `hart_get_status`
+pub const HART_STATE_STARTED: usize = 0;
+pub const HART_STATE_STOPPED: usize = 1;
+pub const HART_STATE_START_PENDING: usize = 2;
+pub const HART_STATE_STOP_PENDING: usize = 3;
+pub const HART_STATE_SUSPENDED: usize = 4;
+
+/// Function IDs and arguments of the SRST extension
+pub const SRST_SYSTEM_RESET: usize = 0;
+pub const RESET_TYPE_SHUTDOWN: usize = 0;
+pub const RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const RESET_TYPE_WARM_REBOOT: usize = 2;
+pub const RESET_REASON_NO_REASON: usize = 0;
+pub const RESET_REASON_SYSTEM_FAILURE: usize = 1;
+
+/// Function IDs of the DBCN extension
+pub const DBCN_CONSOLE_WRITE: usize = 0;
+pub const DBCN_CONSOLE_READ: usize = 1;
+pub const DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+/// Standard SBI error codes
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+
+/// Return value of an SBI call, `a0` and `a1` respectively.
+#[derive(Copy, Clone, Debug)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
 
-    /// Get a reference to the write port
-    fn write_port(&self) -> &'static mut WritePort { unsafe { &mut *(UART_BASE as *mut WritePort) } }
+impl SbiRet {
+    pub fn success(value: usize) -> Self {
+        Self { error: SBI_SUCCESS, value }
+    }
+    pub fn error(error: isize) -> Self {
+        Self { error, value: 0 }
+    }
+    pub fn is_ok(&self) -> bool {
+        self.error == SBI_SUCCESS
+    }
+}
 
-    /// Initialize the UART with standard settings
-    pub fn init(&self) {
-        let read_port = self.read_port();
-        let mut read_port = VolatileRef::from_mut_ref(read_port);
-        let read_port = read_port.as_mut_ptr();
-        let write_port = self.write_port();
-        let mut write_port = VolatileRef::from_mut_ref(write_port);
-        let write_port = write_port.as_mut_ptr();
+#[inline(always)]
+fn sbi_call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a3") arg3,
+            in("a6") fid,
+            in("a7") eid,
+        );
+    }
+    SbiRet { error, value }
+}
 
-        // disable interrupts
-        read_port.ier().write(0);
+pub fn spec_version() -> (usize, usize) {
+    let version = sbi_call(EID_BASE, BASE_GET_SPEC_VERSION, 0, 0, 0, 0).value;
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
 
-        // enable DLAB
-        read_port.lcr().write(flags::LCR_DLAB_ENABLE);
+pub fn impl_id() -> usize {
+    sbi_call(EID_BASE, BASE_GET_IMPL_ID, 0, 0, 0, 0).value
+}
 
-        // set maximum speed of 38.4K for LSB
-        unsafe {
-            (*(read_port.as_raw_ptr().as_ptr())).rbr.store(0x03, Ordering::Release);
-        }
+pub fn impl_version() -> usize {
+    sbi_call(EID_BASE, BASE_GET_IMPL_VERSION, 0, 0, 0, 0).value
+}
 
-        // set maximum speed of 38.4K for MSB
-        read_port.ier().write(0);
+pub fn probe_extension(eid: usize) -> bool {
+    sbi_call(EID_BASE, BASE_PROBE_EXTENSION, eid, 0, 0, 0).value != 0
+}
 
-        // disable DLAB and set data word length to 8 bits
-        read_port.lcr().write(flags::LCR_DATA_8);
+/// Program the next timer interrupt, also clearing the pending one.
+pub fn set_timer(stime_value: u64) {
+    sbi_call(EID_TIME, TIME_SET_TIMER, stime_value as usize, 0, 0, 0);
+}
 
-        // enable FIFO, clear TX/RX queues and set interrupt watermark at 14 bytes
-        // write_port.fcr = flags::FCR_ENABLE | flags::FCR_CLEAR_RX_FIFO |
-        // flags::FCR_CLEAR_TX_FIFO | flags::FCR_TRIGGER_14;
-        write_port.fcr().write(flags::FCR_ENABLE | flags::FCR_CLEAR_RX_FIFO |
-            flags::FCR_CLEAR_TX_FIFO | flags::FCR_TRIGGER_14);
+/// Raise a supervisor software interrupt on every hart in the mask.
+/// `hart_mask_base == usize::MAX` selects all harts.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    sbi_call(EID_IPI, IPI_SEND_IPI, hart_mask, hart_mask_base, 0, 0)
+}
 
-        // mark data terminal ready, signal request to send and enable auxiliary output
-        read_port.mcr().write(flags::MCR_DATA_TERMINAL_READY | flags::MCR_AUXILIARY_OUTPUT_2);
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    sbi_call(EID_RFENCE, RFENCE_REMOTE_FENCE_I, hart_mask, hart_mask_base, 0, 0)
+}
 
-        // enable receive interrupts (we'll poll in read() function)
-        // read_port.ier = flags::IER_RX_AVAILABLE;
-        read_port.ier().write(flags::IER_RX_AVAILABLE);
-    }
+pub fn remote_sfence_vma(hart_mask: usize, hart_mask_base: usize, start: usize, size: usize) -> SbiRet {
+    sbi_call(EID_RFENCE, RFENCE_REMOTE_SFENCE_VMA, hart_mask, hart_mask_base, start, size)
+}
 
-    /// Read a byte from the UART (blocking)
-    pub fn read(&self) -> u8 {
-        let read_port = self.read_port();
-        let lsr = &read_port.lsr;
-        let rbr = &read_port.rbr;
+/// Start a stopped hart at `start_addr` in S-mode, with `a0 = hartid` and `a1 = opaque`.
+/// Paging is off there, so `start_addr` is a physical address.
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    sbi_call(EID_HSM, HSM_HART_START, hartid, start_addr, opaque, 0)
+}
 
-        // Wait until input is available
-        while lsr.load(Ordering::Acquire) & flags::LSR_INPUT_AVAILABLE == 0 {
-            spin_loop();
-        }
+/// Stop the calling hart. Only returns on failure.
+pub fn hart_stop() -> SbiRet {
+    sbi_call(EID_HSM, HSM_HART_STOP, 0, 0, 0, 0)
+}
 
-        // Read the byte
-        rbr.load(Ordering::Acquire)
-    }
+pub fn hart_get_status(hartid: usize) -> SbiRet {
+    sbi_call(EID_HSM, HSM_HART_GET_STATUS, hartid, 0, 0, 0)
+}
 
-    /// Write a byte to the UART
-    pub fn write(&self, data: u8) {
-        let write_port = self.write_port();
-        let lsr = &write_port.lsr;
-        let thr = &mut write_port.thr;
-
-        match data {
-            BS | DEL => {
-                // Wait until output buffer is empty
-                while (lsr.load(Ordering::Acquire) & flags::LSR_OUTPUT_EMPTY) == 0 {
-                    spin_loop();
-                }
-                thr.store(BS, Ordering::Release);
-
-                // Send a space to overwrite the previous character
-                while (lsr.load(Ordering::Acquire) & flags::LSR_OUTPUT_EMPTY) == 0 {
-                    spin_loop();
-                }
-                thr.store(b' ', Ordering::Release);
-
-                // Send another backspace to move cursor back
-                while (lsr.load(Ordering::Acquire) & flags::LSR_OUTPUT_EMPTY) == 0 {
-                    spin_loop();
-                }
-                thr.store(BS, Ordering::Release);
-            }
-            _ => {
-                // Wait until output buffer is empty
-                while (lsr.load(Ordering::Acquire) & flags::LSR_OUTPUT_EMPTY) == 0 {
-                    spin_loop();
-                }
-                thr.store(data, Ordering::Release);
-            }
-        }
-    }
+pub fn system_reset(reset_type: usize, reason: usize) -> SbiRet {
+    sbi_call(EID_SRST, SRST_SYSTEM_RESET, reset_type, reason, 0, 0)
+}
 
-    // Shutdown the machine
-    pub fn shutdown(&self, success: bool) -> ! {
-        let shutdown = SHUTDOWN_BASE as *mut u16;
+/// Shutdown the machine
+pub fn shutdown(success: bool) -> ! {
+    #[cfg(feature = "heap-debug")]
+    crate::mem::heap_allocator::report_leaks();
+    let reason = if success { RESET_REASON_NO_REASON } else { RESET_REASON_SYSTEM_FAILURE };
+    system_reset(RESET_TYPE_SHUTDOWN, reason);
+    // Fall back to the legacy extension for old implementations
+    sbi_call(EID_LEGACY_SHUTDOWN, 0, 0, 0, 0, 0);
+    loop {
         unsafe {
-            shutdown.write_volatile(if success { SHUTDOWN_PASS } else { SHUTDOWN_FAIL });
+            asm!("wfi");
         }
-        loop {}
     }
 }
 
-// Create a global instance
-pub static UART: Uart = Uart;
-
-struct Stdout;
-
-impl Write for Stdout {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.bytes() {
-            UART.write(c);
-        }
-        Ok(())
-    }
+/// Write bytes to the debug console. Returns the number of bytes written.
+/// The SBI takes the buffer by physical address, so it must be in the direct map.
+pub fn console_write(bytes: &[u8]) -> SbiRet {
+    sbi_call(EID_DBCN, DBCN_CONSOLE_WRITE, bytes.len(), virt_to_phys(bytes.as_ptr() as usize), 0, 0)
 }
 
-pub fn print(args: fmt::Arguments) {
-    Stdout.write_fmt(args).unwrap();
+/// Read whatever is available into `buf`, without blocking.
+/// Like `console_write`, `buf` must be in the direct map.
+pub fn console_read(buf: &mut [u8]) -> SbiRet {
+    sbi_call(EID_DBCN, DBCN_CONSOLE_READ, buf.len(), virt_to_phys(buf.as_mut_ptr() as usize), 0, 0)
 }
 
-#[macro_export]
-macro_rules! print {
-    ($fmt: literal $(, $($arg: tt)+)?) => {
-        $crate::sbi::print(format_args!($fmt $(, $($arg)+)?));
-    }
+pub fn console_write_byte(byte: u8) -> SbiRet {
+    sbi_call(EID_DBCN, DBCN_CONSOLE_WRITE_BYTE, byte as usize, 0, 0, 0)
 }
-
-#[macro_export]
-macro_rules! println {
-    ($fmt: literal $(, $($arg: tt)+)?) => {
-        $crate::sbi::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
-    }
-}
\ No newline at end of file
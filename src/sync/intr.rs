@@ -0,0 +1,50 @@
+//! Nested interrupt masking. The state is per hart and lives in its `Cpu`,
+//! since `sstatus.SIE` is per hart too.
+
+use riscv::register::sstatus;
+use crate::cpu;
+
+pub struct IntrMaskingInfo {
+    nested_level: usize,
+    sie_before_masking: bool,
+}
+
+impl IntrMaskingInfo {
+    pub const fn new() -> Self {
+        Self {
+            nested_level: 0,
+            sie_before_masking: false,
+        }
+    }
+
+    pub fn enter(&mut self) {
+        let sie = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        if self.nested_level == 0 {
+            self.sie_before_masking = sie;
+        }
+        self.nested_level += 1;
+    }
+
+    pub fn exit(&mut self) {
+        assert!(self.nested_level > 0, "Unbalanced interrupt masking");
+        self.nested_level -= 1;
+        if self.nested_level == 0 && self.sie_before_masking {
+            unsafe {
+                sstatus::set_sie();
+            }
+        }
+    }
+}
+
+/// Mask interrupts on this hart, remembering whether they were on.
+pub fn intr_masking_enter() {
+    cpu::current().intr_masking().enter();
+}
+
+/// Undo one `intr_masking_enter`; the outermost one restores `sstatus.SIE`.
+pub fn intr_masking_exit() {
+    cpu::current().intr_masking().exit();
+}
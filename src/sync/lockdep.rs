@@ -0,0 +1,405 @@
+//! Lock dependency validator, enabled with the `lockdep` feature.
+//!
+//! Every lock and cell belongs to a class: the place it was created, plus
+//! an optional name given with `named`. Whenever a hart acquires a lock
+//! while holding others, the pairs (held class -> new class) are recorded.
+//! Acquiring a lock whose class is already ordered before one we hold
+//! closes a cycle, i.e. two harts could deadlock on them; acquiring a lock
+//! the hart already holds is a certain deadlock. Both are reported with the
+//! acquisition sites involved, before spinning, and then panic.
+//!
+//! Interrupt context is not tracked: a lock taken both by a handler and,
+//! with interrupts enabled, by the code it interrupts is not caught.
+//!
+//! The bookkeeping uses fixed-size tables so it never allocates: the heap
+//! itself sits behind these locks.
+
+use core::panic::Location;
+
+/// Lockdep identity of a lock; zero-sized unless the feature is enabled.
+#[derive(Copy, Clone)]
+pub struct LockClass {
+    #[cfg(feature = "lockdep")]
+    name: Option<&'static str>,
+    #[cfg(feature = "lockdep")]
+    location: &'static Location<'static>,
+}
+
+impl LockClass {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            name: None,
+            #[cfg(feature = "lockdep")]
+            location: Location::caller(),
+        }
+    }
+
+    #[allow(unused_mut, unused_variables)]
+    pub const fn named(mut self, name: &'static str) -> Self {
+        #[cfg(feature = "lockdep")]
+        {
+            self.name = Some(name);
+        }
+        self
+    }
+}
+
+#[cfg(not(feature = "lockdep"))]
+mod imp {
+    use super::*;
+
+    #[inline(always)]
+    pub fn before_acquire(_: &LockClass, _: usize, _: bool, _: &'static Location<'static>) {}
+    #[inline(always)]
+    pub fn acquired(_: &LockClass, _: usize, _: bool, _: &'static Location<'static>) {}
+    #[inline(always)]
+    pub fn released(_: usize) {}
+}
+
+#[cfg(feature = "lockdep")]
+mod imp {
+    use super::*;
+    use alloc::boxed::Box;
+    use core::cell::UnsafeCell;
+    use core::fmt;
+    use core::hint::spin_loop;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use riscv::register::sstatus;
+    use crate::config::MAX_HARTS;
+    use crate::{console, cpu};
+
+    /// The console locks are checked too, so report without them.
+    macro_rules! println {
+        ($fmt: literal $(, $($arg: tt)+)?) => {
+            console::print_polled(format_args!(concat!($fmt, "\n") $(, $($arg)+)?))
+        };
+    }
+
+    const MAX_CLASSES: usize = 64;
+    /// Locks one hart may hold at the same time
+    const MAX_HELD: usize = 16;
+
+    impl LockClass {
+        fn same(&self, other: &LockClass) -> bool {
+            let (a, b) = (self.location, other.location);
+            a.line() == b.line() && a.column() == b.column() && a.file() == b.file()
+        }
+    }
+
+    impl fmt::Display for LockClass {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self.name {
+                Some(name) => write!(f, "{} (created at {})", name, self.location),
+                None => write!(f, "lock created at {}", self.location),
+            }
+        }
+    }
+
+    type Site = &'static Location<'static>;
+
+    #[derive(Copy, Clone)]
+    struct Edge {
+        /// where the earlier lock was taken
+        held_at: Site,
+        /// where the later lock was taken while holding it
+        acquired_at: Site,
+    }
+
+    struct Graph {
+        classes: [Option<LockClass>; MAX_CLASSES],
+        /// bit `b` of `after[a]`: class `b` was taken while holding class `a`
+        after: [u64; MAX_CLASSES],
+        edges: [[Option<Edge>; MAX_CLASSES]; MAX_CLASSES],
+        overflowed: bool,
+    }
+
+    #[derive(Copy, Clone)]
+    struct Held {
+        class: usize,
+        lock: usize,
+        shared: bool,
+        site: Site,
+    }
+
+    struct HeldStack {
+        locks: [Option<Held>; MAX_HELD],
+        len: usize,
+    }
+
+    /// The graph is guarded by `GRAPH_LOCKED`, each `HELD` entry by masking
+    /// interrupts on its own hart.
+    struct State<T>(UnsafeCell<T>);
+    unsafe impl<T> Sync for State<T> {}
+
+    static GRAPH_LOCKED: AtomicBool = AtomicBool::new(false);
+    static GRAPH: State<Graph> = State(UnsafeCell::new(Graph {
+        classes: [None; MAX_CLASSES],
+        after: [0; MAX_CLASSES],
+        edges: [[None; MAX_CLASSES]; MAX_CLASSES],
+        overflowed: false,
+    }));
+    static HELD: [State<HeldStack>; MAX_HARTS] = [const {
+        State(UnsafeCell::new(HeldStack { locks: [None; MAX_HELD], len: 0 }))
+    }; MAX_HARTS];
+
+    /// Run `f` with interrupts off and the graph locked. Plain CSR accesses
+    /// and an atomic flag, since the kernel locks all call back into here.
+    fn with_state<V>(f: impl FnOnce(&mut Graph, &mut HeldStack) -> V) -> V {
+        let sie = sstatus::read().sie();
+        unsafe {
+            sstatus::clear_sie();
+        }
+        while GRAPH_LOCKED
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let ret = unsafe { f(&mut *GRAPH.0.get(), &mut *HELD[cpu::hartid()].0.get()) };
+        GRAPH_LOCKED.store(false, Ordering::Release);
+        if sie {
+            unsafe {
+                sstatus::set_sie();
+            }
+        }
+        ret
+    }
+
+    impl Graph {
+        fn class_id(&mut self, class: &LockClass) -> Option<usize> {
+            for (id, slot) in self.classes.iter_mut().enumerate() {
+                match slot {
+                    Some(known) if known.same(class) => return Some(id),
+                    Some(_) => {}
+                    None => {
+                        *slot = Some(*class);
+                        return Some(id);
+                    }
+                }
+            }
+            if !self.overflowed {
+                self.overflowed = true;
+                println!("[lockdep] more than {} lock classes, ignoring the rest", MAX_CLASSES);
+            }
+            None
+        }
+
+        fn class(&self, id: usize) -> LockClass {
+            self.classes[id].unwrap()
+        }
+
+        /// Shortest chain of recorded edges `from -> ... -> to`, as `(len, chain)`.
+        fn path(&self, from: usize, to: usize) -> Option<(usize, [usize; MAX_CLASSES])> {
+            let mut parent = [usize::MAX; MAX_CLASSES];
+            let mut queue = [0usize; MAX_CLASSES];
+            let (mut head, mut tail) = (0, 1);
+            queue[0] = from;
+            parent[from] = from;
+            while head < tail {
+                let node = queue[head];
+                head += 1;
+                if node == to {
+                    let mut chain = [0; MAX_CLASSES];
+                    let mut len = 0;
+                    let mut cur = to;
+                    while cur != from {
+                        chain[len] = cur;
+                        len += 1;
+                        cur = parent[cur];
+                    }
+                    chain[len] = from;
+                    chain[..=len].reverse();
+                    return Some((len + 1, chain));
+                }
+                for (next, next_parent) in parent.iter_mut().enumerate() {
+                    if self.after[node] & (1 << next) != 0 && *next_parent == usize::MAX {
+                        *next_parent = node;
+                        queue[tail] = next;
+                        tail += 1;
+                    }
+                }
+            }
+            None
+        }
+    }
+
+    impl HeldStack {
+        fn iter(&self) -> impl Iterator<Item = &Held> {
+            self.locks[..self.len].iter().flatten()
+        }
+    }
+
+    /// Why a hart may not take a lock.
+    #[derive(Copy, Clone)]
+    enum Violation {
+        /// It already holds this very lock.
+        Recursive { holder: Held },
+        /// Class `class` of the lock was ordered before that of `holder`.
+        Cycle { class: usize, holder: Held },
+    }
+
+    /// Check taking `lock` of `class` at `site` while holding `held`, and
+    /// record the new order if that is fine.
+    fn check(
+        graph: &mut Graph,
+        held: &HeldStack,
+        class: &LockClass,
+        lock: usize,
+        shared: bool,
+        site: Site,
+    ) -> Option<Violation> {
+        let id = graph.class_id(class)?;
+        if let Some(&holder) = held.iter().find(|h| h.lock == lock) {
+            // readers of an RwSpinLock never wait for each other
+            if !(shared && holder.shared) {
+                return Some(Violation::Recursive { holder });
+            }
+        }
+        for &holder in held.iter().filter(|h| h.class != id) {
+            if graph.path(id, holder.class).is_some() {
+                return Some(Violation::Cycle { class: id, holder });
+            }
+        }
+        for holder in held.iter().filter(|h| h.class != id) {
+            graph.after[holder.class] |= 1 << id;
+            graph.edges[holder.class][id].get_or_insert(Edge {
+                held_at: holder.site,
+                acquired_at: site,
+            });
+        }
+        None
+    }
+
+    fn report(graph: &Graph, violation: &Violation, class: &LockClass, site: Site) {
+        match *violation {
+            Violation::Recursive { holder } => {
+                println!("[lockdep] recursive acquisition on hart {}", cpu::hartid());
+                println!("[lockdep]   {}", class);
+                println!("[lockdep]   first acquired at {}", holder.site);
+                println!("[lockdep]   acquired again at {}", site);
+            }
+            Violation::Cycle { class: id, holder } => {
+                let (len, chain) = graph.path(id, holder.class).unwrap();
+                println!("[lockdep] possible deadlock on hart {}", cpu::hartid());
+                println!("[lockdep]   acquiring {}", class);
+                println!("[lockdep]     at {}", site);
+                println!("[lockdep]   while holding {}", graph.class(holder.class));
+                println!("[lockdep]     acquired at {}", holder.site);
+                println!("[lockdep]   but the opposite order was seen before:");
+                for pair in chain[..len].windows(2) {
+                    let edge = graph.edges[pair[0]][pair[1]].unwrap();
+                    println!("[lockdep]     {}", graph.class(pair[0]));
+                    println!("[lockdep]       acquired at {}", edge.held_at);
+                    println!("[lockdep]     then {}", graph.class(pair[1]));
+                    println!("[lockdep]       acquired at {}", edge.acquired_at);
+                }
+            }
+        }
+    }
+
+    pub fn before_acquire(class: &LockClass, lock: usize, shared: bool, site: Site) {
+        let violation = with_state(|graph, held| {
+            let violation = check(graph, held, class, lock, shared, site);
+            if let Some(violation) = &violation {
+                report(graph, violation, class, site);
+            }
+            violation
+        });
+        if violation.is_some() {
+            panic!("lockdep: lock order violation");
+        }
+    }
+
+    pub fn acquired(class: &LockClass, lock: usize, shared: bool, site: Site) {
+        with_state(|graph, held| {
+            let Some(class) = graph.class_id(class) else {
+                return;
+            };
+            if held.len == MAX_HELD {
+                println!("[lockdep] more than {} locks held, not tracking {}", MAX_HELD, site);
+                return;
+            }
+            held.locks[held.len] = Some(Held { class, lock, shared, site });
+            held.len += 1;
+        });
+    }
+
+    pub fn released(lock: usize) {
+        with_state(|_, held| {
+            // locks need not be released in order; take the latest entry
+            if let Some(i) = (0..held.len).rev().find(|&i| held.locks[i].is_some_and(|h| h.lock == lock)) {
+                held.locks.copy_within(i + 1..held.len, i);
+                held.len -= 1;
+                held.locks[held.len] = None;
+            }
+        });
+    }
+
+    /// Run `check` on made-up locks, with a graph and held stacks of its
+    /// own: the violations are found without being reported, and the
+    /// classes take no slots in `GRAPH` nor order real locks.
+    pub fn check_test() {
+        // built in place, as a graph is too big for the stack
+        let mut graph = Box::<Graph>::new_uninit();
+        let empty = graph.as_mut_ptr();
+        let mut graph = unsafe {
+            (&raw mut (*empty).classes).write([None; MAX_CLASSES]);
+            (&raw mut (*empty).after).write([0; MAX_CLASSES]);
+            for row in 0..MAX_CLASSES {
+                (&raw mut (*empty).edges[row]).write([None; MAX_CLASSES]);
+            }
+            (&raw mut (*empty).overflowed).write(false);
+            graph.assume_init()
+        };
+        let site = Location::caller();
+        let a = LockClass::new().named("check_test::a");
+        let b = LockClass::new().named("check_test::b");
+        let (lock_a, lock_b) = (1, 2);
+        let holding = |graph: &mut Graph, class: &LockClass, lock: usize, shared: bool| {
+            let mut held = HeldStack { locks: [None; MAX_HELD], len: 1 };
+            let class = graph.class_id(class).unwrap();
+            held.locks[0] = Some(Held { class, lock, shared, site });
+            held
+        };
+        let mut try_lock = |held: (&LockClass, usize, bool), class: &LockClass, lock: usize, shared: bool| {
+            let held = holding(&mut graph, held.0, held.1, held.2);
+            check(&mut graph, &held, class, lock, shared, site)
+        };
+        // A then B is recorded, after which B then A closes the cycle
+        assert!(try_lock((&a, lock_a, false), &b, lock_b, false).is_none());
+        let violation = try_lock((&b, lock_b, false), &a, lock_a, false);
+        assert!(matches!(violation, Some(Violation::Cycle { holder, .. }) if holder.lock == lock_b));
+        // taking a held lock again, except for a second reader
+        let violation = try_lock((&a, lock_a, false), &a, lock_a, false);
+        assert!(matches!(violation, Some(Violation::Recursive { holder }) if holder.lock == lock_a));
+        assert!(try_lock((&a, lock_a, true), &a, lock_a, false).is_some());
+        assert!(try_lock((&a, lock_a, true), &a, lock_a, true).is_none());
+    }
+}
+
+pub use imp::{acquired, before_acquire, released};
+
+#[allow(unused)]
+pub fn lockdep_test() {
+    use super::{SpinLock, SpinLockIrq, UPSafeCell};
+    let a = SpinLock::new(0).named("lockdep_test::a");
+    let b = SpinLockIrq::new(0).named("lockdep_test::b");
+    let c = unsafe { UPSafeCell::new(0).named("lockdep_test::c") };
+    for _ in 0..2 {
+        let _a = a.lock();
+        let _b = b.lock();
+        *c.exclusive_access() += 1;
+    }
+    {
+        // taking only part of a known chain is fine
+        let _a = a.lock();
+        *c.exclusive_access() += 1;
+    }
+    assert_eq!(*c.exclusive_access(), 3);
+    // what the locks would panic on
+    #[cfg(feature = "lockdep")]
+    imp::check_test();
+    crate::println!("lockdep_test passed!");
+}
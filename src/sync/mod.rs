@@ -0,0 +1,19 @@
+mod intr;
+mod lockdep;
+mod spin;
+mod up;
+
+pub use intr::{intr_masking_enter, intr_masking_exit, IntrMaskingInfo};
+#[allow(unused)]
+pub use spin::{
+    RwSpinLock, RwSpinLockReadGuard, RwSpinLockWriteGuard, SpinLock, SpinLockGuard, SpinLockIrq,
+    SpinLockIrqGuard, TicketLock, TicketLockGuard,
+};
+#[allow(unused)]
+pub use lockdep::LockClass;
+#[allow(unused)]
+pub use up::{UPIntrFreeCell, UPIntrRefMut, UPRefMut, UPSafeCell, UPSafeCellRaw};
+#[allow(unused)]
+pub use lockdep::lockdep_test;
+#[allow(unused)]
+pub use spin::lock_test;
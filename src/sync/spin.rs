@@ -0,0 +1,490 @@
+//! Spinning locks, safe to share between harts.
+//!
+//! Their guards deref to the protected data like `UPIntrRefMut`, and the
+//! exclusive locks also offer `exclusive_access`/`exclusive_session`, so a
+//! `UPSafeCell` or `UPIntrFreeCell` can be swapped for one of them without
+//! touching its call sites.
+//!
+//! Locks are checked by lockdep when it is enabled; give the important ones
+//! a name with `named`, e.g. `SpinLock::new(..).named("FRAME_ALLOCATOR")`.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+use super::intr::{intr_masking_enter, intr_masking_exit};
+use super::lockdep::{self, LockClass};
+use crate::println;
+
+/// Test-and-set spinlock.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    class: LockClass,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            class: LockClass::new(),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub const fn named(mut self, name: &'static str) -> Self {
+        self.class = self.class.named(name);
+        self
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let site = Location::caller();
+        lockdep::before_acquire(&self.class, self.addr(), false, site);
+        while !self.raw_try_lock() {
+            // wait on a plain load so the cache line is not bounced around
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+        lockdep::acquired(&self.class, self.addr(), false, site);
+        SpinLockGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        if !self.raw_try_lock() {
+            return None;
+        }
+        lockdep::acquired(&self.class, self.addr(), false, Location::caller());
+        Some(SpinLockGuard { lock: self })
+    }
+
+    fn raw_try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Same as `lock`, named after `UPSafeCell::exclusive_access`.
+    #[track_caller]
+    #[allow(unused)]
+    pub fn exclusive_access(&self) -> SpinLockGuard<'_, T> {
+        self.lock()
+    }
+
+    #[track_caller]
+    #[allow(unused)]
+    pub fn exclusive_session<F, V>(&self, f: F) -> V
+    where
+        F: FnOnce(&mut T) -> V,
+    {
+        f(&mut self.lock())
+    }
+
+    #[allow(unused)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.lock.addr());
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+/// FIFO spinlock: harts get the lock in the order they asked for it.
+pub struct TicketLock<T> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    class: LockClass,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+unsafe impl<T: Send> Send for TicketLock<T> {}
+
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> TicketLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            class: LockClass::new(),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    #[allow(unused)]
+    pub const fn named(mut self, name: &'static str) -> Self {
+        self.class = self.class.named(name);
+        self
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T> {
+        let site = Location::caller();
+        lockdep::before_acquire(&self.class, self.addr(), false, site);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        lockdep::acquired(&self.class, self.addr(), false, site);
+        TicketLockGuard { lock: self }
+    }
+
+    /// Only succeeds if nobody holds or waits for the lock.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T>> {
+        let ticket = self.now_serving.load(Ordering::Acquire);
+        self.next_ticket
+            .compare_exchange(ticket, ticket + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        lockdep::acquired(&self.class, self.addr(), false, Location::caller());
+        Some(TicketLockGuard { lock: self })
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[allow(unused)]
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    /// Same as `lock`, named after `UPSafeCell::exclusive_access`.
+    #[track_caller]
+    #[allow(unused)]
+    pub fn exclusive_access(&self) -> TicketLockGuard<'_, T> {
+        self.lock()
+    }
+
+    #[track_caller]
+    pub fn exclusive_session<F, V>(&self, f: F) -> V
+    where
+        F: FnOnce(&mut T) -> V,
+    {
+        f(&mut self.lock())
+    }
+}
+
+impl<'a, T> Drop for TicketLockGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.lock.addr());
+        // only the holder writes now_serving
+        let next = self.lock.now_serving.load(Ordering::Relaxed) + 1;
+        self.lock.now_serving.store(next, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for TicketLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+impl<'a, T> DerefMut for TicketLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+/// Readers-writer spinlock. Readers share the lock; a writer waits until
+/// they are all gone, so a steady stream of readers can starve it.
+pub struct RwSpinLock<T> {
+    /// `WRITER`, or the number of readers times `READER`
+    state: AtomicUsize,
+    class: LockClass,
+    inner: UnsafeCell<T>,
+}
+
+const WRITER: usize = 1;
+const READER: usize = 2;
+
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> {}
+unsafe impl<T: Send> Send for RwSpinLock<T> {}
+
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+}
+
+impl<T> RwSpinLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            class: LockClass::new(),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub const fn named(mut self, name: &'static str) -> Self {
+        self.class = self.class.named(name);
+        self
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let site = Location::caller();
+        lockdep::before_acquire(&self.class, self.addr(), true, site);
+        while !self.raw_try_read() {
+            while self.state.load(Ordering::Relaxed) & WRITER != 0 {
+                spin_loop();
+            }
+        }
+        lockdep::acquired(&self.class, self.addr(), true, site);
+        RwSpinLockReadGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        if !self.raw_try_read() {
+            return None;
+        }
+        lockdep::acquired(&self.class, self.addr(), true, Location::caller());
+        Some(RwSpinLockReadGuard { lock: self })
+    }
+
+    fn raw_try_read(&self) -> bool {
+        let state = self.state.fetch_add(READER, Ordering::Acquire);
+        if state & WRITER != 0 {
+            self.state.fetch_sub(READER, Ordering::Release);
+            false
+        } else {
+            true
+        }
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let site = Location::caller();
+        lockdep::before_acquire(&self.class, self.addr(), false, site);
+        while !self.raw_try_write() {
+            while self.state.load(Ordering::Relaxed) != 0 {
+                spin_loop();
+            }
+        }
+        lockdep::acquired(&self.class, self.addr(), false, site);
+        RwSpinLockWriteGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        if !self.raw_try_write() {
+            return None;
+        }
+        lockdep::acquired(&self.class, self.addr(), false, Location::caller());
+        Some(RwSpinLockWriteGuard { lock: self })
+    }
+
+    fn raw_try_write(&self) -> bool {
+        self.state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[allow(unused)]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.lock.addr());
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+    }
+}
+
+impl<'a, T> Drop for RwSpinLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.lock.addr());
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for RwSpinLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T> Deref for RwSpinLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+impl<'a, T> DerefMut for RwSpinLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+/// Spinlock that also masks interrupts on the holding hart, so it can be
+/// shared with interrupt handlers. The SMP counterpart of `UPIntrFreeCell`.
+pub struct SpinLockIrq<T> {
+    inner: SpinLock<T>,
+}
+
+pub struct SpinLockIrqGuard<'a, T>(Option<SpinLockGuard<'a, T>>);
+
+impl<T> SpinLockIrq<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinLock::new(value),
+        }
+    }
+
+    pub const fn named(mut self, name: &'static str) -> Self {
+        self.inner.class = self.inner.class.named(name);
+        self
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockIrqGuard<'_, T> {
+        intr_masking_enter();
+        SpinLockIrqGuard(Some(self.inner.lock()))
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockIrqGuard<'_, T>> {
+        intr_masking_enter();
+        match self.inner.try_lock() {
+            Some(guard) => Some(SpinLockIrqGuard(Some(guard))),
+            None => {
+                intr_masking_exit();
+                None
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Same as `lock`, named after `UPIntrFreeCell::exclusive_access`.
+    #[track_caller]
+    pub fn exclusive_access(&self) -> SpinLockIrqGuard<'_, T> {
+        self.lock()
+    }
+
+    #[track_caller]
+    #[allow(unused)]
+    pub fn exclusive_session<F, V>(&self, f: F) -> V
+    where
+        F: FnOnce(&mut T) -> V,
+    {
+        f(&mut self.lock())
+    }
+}
+
+impl<'a, T> Drop for SpinLockIrqGuard<'a, T> {
+    fn drop(&mut self) {
+        // release the lock before interrupts can come back
+        self.0 = None;
+        intr_masking_exit();
+    }
+}
+
+impl<'a, T> Deref for SpinLockIrqGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().unwrap().deref()
+    }
+}
+impl<'a, T> DerefMut for SpinLockIrqGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().unwrap().deref_mut()
+    }
+}
+
+#[allow(unused)]
+pub fn lock_test() {
+    let lock = SpinLock::new(0);
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(lock.try_lock().is_none());
+    }
+    assert_eq!(*lock.lock(), 1);
+
+    let lock = TicketLock::new(0);
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(lock.try_lock().is_none());
+    }
+    assert_eq!(lock.exclusive_session(|v| *v), 1);
+
+    let lock = RwSpinLock::new(0);
+    {
+        let (a, b) = (lock.read(), lock.read());
+        assert_eq!(*a + *b, 0);
+        assert!(lock.try_write().is_none());
+    }
+    *lock.write() += 1;
+    assert!(lock.try_read().is_some());
+
+    let sie = sstatus::read().sie();
+    let lock = SpinLockIrq::new(0);
+    {
+        let mut outer = lock.lock();
+        assert!(!sstatus::read().sie());
+        *outer += 1;
+        let inner = SpinLockIrq::new(0);
+        drop(inner.lock());
+        // still masked: the outer guard is alive
+        assert!(!sstatus::read().sie());
+    }
+    assert_eq!(sstatus::read().sie(), sie);
+    println!("lock_test passed!");
+}
@@ -0,0 +1,146 @@
+use core::cell::{RefCell, RefMut, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use super::intr::{intr_masking_enter, intr_masking_exit};
+use super::lockdep::{self, LockClass};
+
+pub struct UPSafeCell<T> {
+    /// inner data
+    inner: RefCell<T>,
+    class: LockClass,
+}
+
+unsafe impl<T> Sync for UPSafeCell<T> {}
+
+pub struct UPRefMut<'a, T>(RefMut<'a, T>, usize);
+
+impl<T> UPSafeCell<T> {
+    /// User is responsible to guarantee that inner struct is only used in
+    /// uni-processor.
+    #[track_caller]
+    pub unsafe fn new(value: T) -> Self {
+        Self {
+            inner: RefCell::new(value),
+            class: LockClass::new(),
+        }
+    }
+
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.class = self.class.named(name);
+        self
+    }
+
+    /// Panic if the data has been borrowed.
+    #[track_caller]
+    pub fn exclusive_access(&self) -> UPRefMut<'_, T> {
+        let (addr, site) = (self as *const Self as usize, Location::caller());
+        lockdep::before_acquire(&self.class, addr, false, site);
+        let inner = self.inner.borrow_mut();
+        lockdep::acquired(&self.class, addr, false, site);
+        UPRefMut(inner, addr)
+    }
+}
+
+impl<'a, T> Drop for UPRefMut<'a, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.1);
+    }
+}
+
+impl<'a, T> Deref for UPRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.0.deref()
+    }
+}
+impl<'a, T> DerefMut for UPRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.deref_mut()
+    }
+}
+
+pub struct UPSafeCellRaw<T> {
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for UPSafeCellRaw<T> {}
+
+#[allow(unused)]
+impl<T> UPSafeCellRaw<T> {
+    pub unsafe fn new(value: T) -> Self {
+        Self {
+            inner: UnsafeCell::new(value),
+        }
+    }
+    // `new` is unsafe: its caller vouches that accesses never overlap
+    #[allow(clippy::mut_from_ref)]
+    pub fn get_mut(&self) -> &mut T {
+        unsafe { &mut (*self.inner.get()) }
+    }
+}
+
+pub struct UPIntrFreeCell<T> {
+    /// inner data
+    inner: RefCell<T>,
+    class: LockClass,
+}
+
+unsafe impl<T> Sync for UPIntrFreeCell<T> {}
+
+pub struct UPIntrRefMut<'a, T>(Option<RefMut<'a, T>>, usize);
+
+#[allow(unused)]
+impl<T> UPIntrFreeCell<T> {
+    #[track_caller]
+    pub unsafe fn new(value: T) -> Self {
+        Self {
+            inner: RefCell::new(value),
+            class: LockClass::new(),
+        }
+    }
+
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.class = self.class.named(name);
+        self
+    }
+
+    /// Panic if the data has been borrowed.
+    #[track_caller]
+    pub fn exclusive_access(&self) -> UPIntrRefMut<'_, T> {
+        let (addr, site) = (self as *const Self as usize, Location::caller());
+        intr_masking_enter();
+        lockdep::before_acquire(&self.class, addr, false, site);
+        let inner = self.inner.borrow_mut();
+        lockdep::acquired(&self.class, addr, false, site);
+        UPIntrRefMut(Some(inner), addr)
+    }
+
+    #[track_caller]
+    pub fn exclusive_session<F, V>(&self, f: F) -> V
+    where
+        F: FnOnce(&mut T) -> V,
+    {
+        let mut inner = self.exclusive_access();
+        f(inner.deref_mut())
+    }
+}
+
+impl<'a, T> Drop for UPIntrRefMut<'a, T> {
+    fn drop(&mut self) {
+        self.0 = None;
+        lockdep::released(self.1);
+        intr_masking_exit();
+    }
+}
+
+impl<'a, T> Deref for UPIntrRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().unwrap().deref()
+    }
+}
+impl<'a, T> DerefMut for UPIntrRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().unwrap().deref_mut()
+    }
+}
\ No newline at end of file
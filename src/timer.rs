@@ -0,0 +1,223 @@
+//! Timekeeping and one-shot timers.
+//!
+//! `now()` is monotonic time in nanoseconds, converted from the `time` CSR
+//! with the device tree's `timebase-frequency`. Each hart keeps a queue of
+//! pending timers and programs its next deadline either through the SBI
+//! TIME extension or, with Sstc, straight into `stimecmp`. The scheduler
+//! tick, sleeps and timeouts are all timers on these queues.
+
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use core::arch::asm;
+use core::cmp::Ordering as CmpOrdering;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv::register::{sie, sstatus, time};
+use crate::config::{CLOCK_FREQ, MAX_HARTS, TICKS_PER_SEC};
+use crate::cpu;
+use crate::platform::PLATFORM;
+use crate::println;
+use crate::sbi::set_timer;
+use crate::sync::SpinLockIrq;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+pub const NSEC_PER_MSEC: u64 = 1_000_000;
+
+/// Timer interrupts taken since boot, one every `1 / TICKS_PER_SEC` seconds.
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+/// `timebase-frequency` from the device tree, cached for interrupt context.
+static TIMEBASE_FREQ: AtomicUsize = AtomicUsize::new(CLOCK_FREQ);
+/// Program deadlines through `stimecmp` instead of SBI.
+static USE_SSTC: AtomicBool = AtomicBool::new(false);
+
+pub type TimerId = usize;
+static NEXT_TIMER_ID: AtomicUsize = AtomicUsize::new(1);
+
+struct Timer {
+    /// in ns, compared against `now()`
+    deadline: u64,
+    id: TimerId,
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+// BinaryHeap is a max-heap: the earliest deadline is the greatest
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.deadline, other.id).cmp(&(self.deadline, self.id))
+    }
+}
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+impl Eq for Timer {}
+
+static TIMER_QUEUES: [SpinLockIrq<BinaryHeap<Timer>>; MAX_HARTS] =
+    [const { SpinLockIrq::new(BinaryHeap::new()).named("TIMER_QUEUES") }; MAX_HARTS];
+
+/// Raw `time` CSR value, in timebase ticks.
+pub fn get_time() -> usize {
+    time::read()
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * NSEC_PER_SEC as u128 / TIMEBASE_FREQ.load(Ordering::Relaxed) as u128) as u64
+}
+
+/// The first tick at which `now()` has reached `ns`.
+fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * TIMEBASE_FREQ.load(Ordering::Relaxed) as u128).div_ceil(NSEC_PER_SEC as u128) as u64
+}
+
+/// Monotonic time since reset, in nanoseconds.
+pub fn now() -> u64 {
+    ticks_to_ns(get_time() as u64)
+}
+
+/// Pick the timebase and the deadline backend from the device tree.
+pub fn init() {
+    let platform = PLATFORM.read();
+    TIMEBASE_FREQ.store(platform.timebase_frequency, Ordering::Relaxed);
+    USE_SSTC.store(platform.has_sstc, Ordering::Relaxed);
+}
+
+/// Let the calling hart take timer interrupts, with no deadline armed yet.
+pub fn init_hart() {
+    program_deadline(None);
+    unsafe {
+        sie::set_stimer();
+    }
+}
+
+/// Start the scheduler tick on the calling hart and enable interrupts.
+pub fn enable_timer_interrupt() {
+    init_hart();
+    add_timer(now() + tick_interval(), tick);
+    unsafe {
+        sstatus::set_sie();
+    }
+}
+
+pub fn get_ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn tick_interval() -> u64 {
+    NSEC_PER_SEC / TICKS_PER_SEC as u64
+}
+
+/// The periodic tick, re-armed relative to its own deadline so it doesn't drift.
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let next = now() + tick_interval();
+    add_timer(next - next % tick_interval(), tick);
+}
+
+/// Program the next timer interrupt of the calling hart; `None` disarms it.
+/// Programming a deadline also clears the pending timer interrupt.
+fn program_deadline(deadline: Option<u64>) {
+    // rounding down would fire early, and handle_interrupt would re-arm
+    // the same deadline over and over until it passed
+    let stime = deadline.map_or(u64::MAX, ns_to_ticks);
+    if USE_SSTC.load(Ordering::Relaxed) {
+        unsafe { asm!("csrw 0x14d, {}", in(reg) stime) }; // stimecmp
+    } else {
+        set_timer(stime);
+    }
+}
+
+/// Run `callback` in interrupt context on the calling hart once `now()`
+/// reaches `deadline`. Deadlines in the past fire on the next interrupt.
+pub fn add_timer(deadline: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+    let mut queue = TIMER_QUEUES[cpu::hartid()].lock();
+    let earliest = queue.peek().is_none_or(|first| deadline < first.deadline);
+    queue.push(Timer { deadline, id, callback: Box::new(callback) });
+    if earliest {
+        program_deadline(Some(deadline));
+    }
+    id
+}
+
+/// Remove a timer that has not fired yet; returns whether it was pending.
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMER_QUEUES.iter().any(|queue| {
+        let mut queue = queue.lock();
+        let len = queue.len();
+        queue.retain(|timer| timer.id != id);
+        queue.len() != len
+    })
+}
+
+/// Called by the trap handler on a supervisor timer interrupt: run every
+/// expired timer, then arm the next deadline.
+pub fn handle_interrupt() {
+    let queue = &TIMER_QUEUES[cpu::hartid()];
+    loop {
+        let expired = {
+            let mut queue = queue.lock();
+            match queue.peek() {
+                Some(first) if first.deadline <= now() => queue.pop(),
+                first => {
+                    program_deadline(first.map(|timer| timer.deadline));
+                    None
+                }
+            }
+        };
+        match expired {
+            // not under the lock: callbacks may add timers
+            Some(timer) => (timer.callback)(),
+            None => break,
+        }
+    }
+}
+
+/// Wait (with `wfi`) until `deadline`. Needs interrupts enabled.
+pub fn sleep_until(deadline: u64) {
+    if now() >= deadline {
+        return;
+    }
+    let done = alloc::sync::Arc::new(AtomicBool::new(false));
+    let flag = done.clone();
+    add_timer(deadline, move || flag.store(true, Ordering::Release));
+    while !done.load(Ordering::Acquire) {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+pub fn sleep(ns: u64) {
+    sleep_until(now() + ns);
+}
+
+#[allow(unused)]
+pub fn timer_test() {
+    let start = get_ticks();
+    while get_ticks() < start + 10 {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+
+    let fired = alloc::sync::Arc::new(AtomicUsize::new(0));
+    let (first, second) = (fired.clone(), fired.clone());
+    let begin = now();
+    add_timer(begin + 20 * NSEC_PER_MSEC, move || {
+        assert_eq!(first.fetch_add(1, Ordering::AcqRel), 1, "timers fired out of order");
+    });
+    add_timer(begin + 10 * NSEC_PER_MSEC, move || {
+        assert_eq!(second.fetch_add(1, Ordering::AcqRel), 0, "timers fired out of order");
+    });
+    let cancelled = add_timer(begin + 15 * NSEC_PER_MSEC, || panic!("cancelled timer fired"));
+    assert!(cancel_timer(cancelled));
+    sleep(30 * NSEC_PER_MSEC);
+    assert!(now() - begin >= 30 * NSEC_PER_MSEC);
+    assert_eq!(fired.load(Ordering::Acquire), 2);
+    println!("timer_test passed! ({} ticks since boot)", get_ticks());
+}
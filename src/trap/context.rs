@@ -0,0 +1,10 @@
+use riscv::register::sstatus::Sstatus;
+
+/// Registers saved by `__alltraps` on the interrupted stack.
+#[repr(C)]
+pub struct TrapContext {
+    /// General-purpose registers x0~x31
+    pub x: [usize; 32],
+    pub sstatus: Sstatus,
+    pub sepc: usize,
+}
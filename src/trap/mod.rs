@@ -0,0 +1,142 @@
+mod context;
+
+use core::arch::{asm, global_asm};
+use riscv::interrupt::supervisor::{Exception, Interrupt};
+use riscv::interrupt::Trap;
+use riscv::register::stvec::{Stvec, TrapMode};
+use riscv::register::{scause, stval, stvec};
+use crate::config::{KERNEL_STACK_SIZE, MAX_KERNEL_STACKS, PAGE_SIZE};
+use crate::drivers::plic;
+use crate::mem::guard_page_owner;
+use crate::{cpu, print, println, timer};
+
+pub use context::TrapContext;
+
+global_asm!(
+    include_str!("trap.S"),
+    KERNEL_STACK_SIZE = const KERNEL_STACK_SIZE,
+    KERNEL_STACK_STRIDE = const KERNEL_STACK_SIZE + PAGE_SIZE,
+    KERNEL_STACKS_SPAN = const MAX_KERNEL_STACKS * (KERNEL_STACK_SIZE + PAGE_SIZE),
+    TRAP_SCRATCH = const cpu::TRAP_SCRATCH_OFFSET,
+    OVERFLOW_STACK_TOP = const cpu::OVERFLOW_STACK_TOP_OFFSET,
+);
+
+pub fn init() {
+    set_kernel_trap_entry();
+}
+
+fn set_kernel_trap_entry() {
+    unsafe extern "C" {
+        fn __alltraps();
+    }
+    let mut vec = Stvec::from_bits(0);
+    vec.set_address(__alltraps as usize);
+    vec.set_trap_mode(TrapMode::Direct);
+    unsafe {
+        stvec::write(vec);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn trap_handler(cx: &mut TrapContext) {
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause().try_into::<Interrupt, Exception>() {
+        Ok(Trap::Interrupt(interrupt)) => handle_interrupt(cx, interrupt),
+        Ok(Trap::Exception(exception)) => handle_exception(cx, exception, stval),
+        Err(_) => {
+            dump_context(cx);
+            panic!(
+                "Unknown trap {:?}, stval = {:#x}, sepc = {:#x}",
+                scause.cause(),
+                stval,
+                cx.sepc
+            );
+        }
+    }
+}
+
+fn handle_interrupt(cx: &mut TrapContext, interrupt: Interrupt) {
+    match interrupt {
+        Interrupt::SupervisorTimer => timer::handle_interrupt(),
+        Interrupt::SupervisorExternal => plic::handle_interrupt(),
+        Interrupt::SupervisorSoft => {
+            dump_context(cx);
+            panic!("Unsupported interrupt {:?}, sepc = {:#x}", interrupt, cx.sepc);
+        }
+    }
+}
+
+fn handle_exception(cx: &mut TrapContext, exception: Exception, stval: usize) {
+    match exception {
+        Exception::Breakpoint => {
+            println!("[kernel] Breakpoint at {:#x}", cx.sepc);
+            cx.sepc += instruction_len(cx.sepc);
+        }
+        Exception::IllegalInstruction => {
+            dump_context(cx);
+            panic!(
+                "IllegalInstruction in kernel, instruction = {:#x}, sepc = {:#x}",
+                stval, cx.sepc
+            );
+        }
+        Exception::LoadPageFault | Exception::StorePageFault if let Some(id) = guard_page_owner(stval) => {
+            dump_context(cx);
+            panic!(
+                "kernel stack overflow in task {}, bad addr = {:#x}, sepc = {:#x}",
+                id, stval, cx.sepc
+            );
+        }
+        Exception::InstructionPageFault
+        | Exception::LoadPageFault
+        | Exception::StorePageFault
+        | Exception::InstructionFault
+        | Exception::LoadFault
+        | Exception::StoreFault
+        | Exception::InstructionMisaligned
+        | Exception::LoadMisaligned
+        | Exception::StoreMisaligned => {
+            dump_context(cx);
+            panic!(
+                "{:?} in kernel, bad addr = {:#x}, sepc = {:#x}",
+                exception, stval, cx.sepc
+            );
+        }
+        Exception::UserEnvCall | Exception::SupervisorEnvCall => {
+            dump_context(cx);
+            panic!("Unexpected {:?}, sepc = {:#x}", exception, cx.sepc);
+        }
+    }
+}
+
+/// Length in bytes of the instruction at `addr`, which may be compressed.
+fn instruction_len(addr: usize) -> usize {
+    let low = unsafe { (addr as *const u16).read_volatile() };
+    if low & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+fn dump_context(cx: &TrapContext) {
+    println!("sepc: {:#018x}  sstatus: {:#018x}", cx.sepc, cx.sstatus.bits());
+    for (i, name) in REG_NAMES.iter().enumerate().skip(1) {
+        print!("{:>4}: {:#018x}", name, cx.x[i]);
+        if i % 4 == 3 {
+            println!();
+        } else {
+            print!("  ");
+        }
+    }
+}
+
+#[allow(unused)]
+pub fn trap_test() {
+    unsafe {
+        asm!("ebreak");
+    }
+    println!("trap_test passed!");
+}